@@ -1,48 +1,156 @@
+// returning explicitly, spelling out every field and matching to a bool are the style here,
+// and quicksilver's Error is large but is what everything returns
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::match_like_matches_macro,
+         clippy::result_large_err)]
+
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::collections::BTreeMap;
 
 use rand::*;
+use rand::rngs::StdRng;
 use noise::*;
 
+use serde::{Serialize, Deserialize};
+
 use quicksilver::prelude::*;
+use quicksilver::saving;
+use quicksilver::graphics::{ResizeStrategy, View};
+use quicksilver::combinators::join_all;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod terminal;
 
 
 const NUM_LEVEL_GAME: usize = 4;
 
+const APP_NAME: &str = "stone_fall";
+const HIGH_SCORE_PROFILE: &str = "high_scores";
+const CONFIG_PROFILE: &str = "settings";
+const NUM_HIGH_SCORES: usize = 10;
+const MAX_NAME_LENGTH: usize = 12;
+const MAX_SEED_DIGITS: usize = 10;
+const SUMMARY_LINE_CHARS: usize = 64;
+
+const SCORE_PER_LEVEL: i32 = 1000;
+const SCORE_PER_HP: i32 = 100;
+const SCORE_PER_TRAP_KILL: i32 = 150;
+const SCORE_PER_COMBAT_KILL: i32 = 50;
+const SCORE_PER_TURN: i32 = -5;
+const SCORE_PER_UNDO: i32 = -25;
+
 const BACKGROUND_COLOR: Color = Color::BLACK;
 const SCALE: f32 = 2.5;
 
 const WALL_CHAR: char = 2 as char;
+const GOL_CHAR: char = 152 as char;
+const ROOK_CHAR: char = 130 as char;
+const KILL_TRAP_CHAR: char = 147 as char;
+const ARROW_LEFT_CHAR: char = 17 as char;
+const ARROW_RIGHT_CHAR: char = 16 as char;
+const ARROW_UP_CHAR: char = 18 as char;
+const ARROW_DOWN_CHAR: char = 19 as char;
+const NEXT_LEVEL_CHAR: char = 3 as char;
+const WIN_CHAR: char = 255 as char;
 const ITERP_TIME: f64 = 0.15;
-const DRAWS_PER_IDLE_FRAME: usize = 2;
-const DRAWS_PER_ATTACK_FRAME: usize = 1;
-const DRAWS_PER_DEATH_FRAME: usize = 2;
-
+// seconds for each tile of an arrow trap slide, a teleport and a bump trap shake
+const SLIDE_STEP_TIME: f64 = 0.06;
+const TELEPORT_TIME: f64 = 0.4;
+const SHAKE_TIME: f64 = 0.25;
+// how far a shake moves an entity, in tiles
+const SHAKE_DISTANCE: f32 = 0.1;
+const DRAW_RATE_MS: f64 = 100.0;
+const ANIMATION_MANIFEST: &str = "animations.json";
+const PALETTE_FILES: [&str; 4] =
+    ["palettes/default.json", "palettes/deuteranopia.json",
+     "palettes/protanopia.json", "palettes/high_contrast.json"];
+
+// the game is laid out at this size, and Layout scales it to the window
 const WINDOW_WIDTH: u32 = 800;
 const WINDOW_HEIGHT: u32 = 600;
 
 const MAP_WIDTH: usize = 10;
 const MAP_HEIGHT: usize = 10;
 
+// positions within the layout, before it is scaled to the window
 const MAP_DRAW_X_OFFSET: usize  = 200;
 const MAP_DRAW_Y_OFFSET: usize  = 120;
 const TILE_WIDTH_PX: u32 = 35;
 const TILE_HEIGHT_PX: u32 = 35;
 
 const MILLIS_PER_UPDATE: f64 = 0.5;
+// longer gaps between frames, such as when the window is in the
+// background, are treated as this many seconds
+const MAX_FRAME_TIME: f64 = 0.25;
+#[allow(dead_code)]
 const IDLE_PROB: f32 = 1.0;
 const PLAYER_CHARACTER: char = 139 as char;
 
+// analog stick movement smaller than this is ignored
+const STICK_DEAD_ZONE: f32 = 0.4;
+// holding a direction on the gamepad repeats it, first after
+// STICK_REPEAT_DELAY seconds and then every STICK_REPEAT_INTERVAL seconds
+const STICK_REPEAT_DELAY: f64 = 0.3;
+const STICK_REPEAT_INTERVAL: f64 = 0.15;
+
+// music tracks are this long, and are played again when they finish
+const MUSIC_TRACK_SECONDS: f64 = 8.0;
+const VOLUME_STEP: u8 = 10;
+
+// the player flashes red for this long after being hurt,
+// switching color every HURT_FLASH_PERIOD seconds
+const HURT_FLASH_TIME: f64 = 0.4;
+const HURT_FLASH_PERIOD: f64 = 0.1;
+// time for the player's death animation before the lose screen
+const LOSE_SCREEN_DELAY: f64 = 1.5;
+
+// kill traps shake the screen, and the screen flashes red when the player is hurt
+const SCREEN_SHAKE_TIME: f64 = 0.3;
+const SCREEN_SHAKE_PX: f32 = 6.0;
+const SCREEN_FLASH_TIME: f64 = 0.3;
+const SCREEN_FLASH_ALPHA: f32 = 0.35;
+// bump traps throw up dust, and teleports sparkle
+const DUST_PARTICLES: usize = 10;
+const SPARKLE_PARTICLES: usize = 12;
+const PARTICLE_LIFE: f64 = 0.6;
+const PARTICLE_SIZE_PX: f32 = 3.0;
+
+// the message log panel to the left of the map
+const LOG_LINE_CHARS: usize = 24;
+const LOG_VISIBLE_LINES: usize = 20;
+const LOG_LINE_HEIGHT_PX: f32 = 16.0;
+const LOG_TEXT_SIZE: f32 = 12.0;
+const MAX_LOG_MESSAGES: usize = 500;
+// thickness of the frame drawn around traps by palettes with outlines
+const OUTLINE_WIDTH_PX: f32 = 2.0;
+
+const INTENT_WIDTH_PX: f32 = 2.0;
+const INTENT_ALPHA: f32 = 0.8;
+const THREAT_ALPHA: f32 = 0.15;
+const PREVIEW_ALPHA: f32 = 0.9;
+const CHAIN_ALPHA: f32 = 0.5;
+const MAX_PREVIEW_TURNS: usize = 4;
+
+const ENTITY_BAR_WIDTH_PX: f32 = 28.0;
+const ENTITY_BAR_HEIGHT_PX: f32 = 4.0;
+const STATUS_TEXT_SIZE: f32 = 16.0;
+
+// traps within this many tiles of the player are revealed
+const TRAP_DETECTION_RADIUS: f32 = 1.5;
+
+// an entity acts once for every ACTION_ENERGY it has accumulated.
+// a speed of NORMAL_SPEED gives one action per player turn.
+const NORMAL_SPEED: i32 = 100;
+const ACTION_ENERGY: i32 = 100;
+
 static RED: Color         = Color { r: 161.0 / 255.0, g: 22.0  / 255.0, b: 52.0  / 255.0, a: 1.0 };
-static DARK_GREEN: Color  = Color { r: 25.0  / 255.0, g: 69.0  / 255.0, b: 35.0  / 255.0, a: 1.0 };
-static GREEN: Color       = Color { r: 15.0  / 255.0, g: 128.0 / 255.0, b: 55.0  / 255.0, a: 1.0 };
 static BRIGHT_BLUE: Color = Color { r: 101.0 / 255.0, g: 233.0 / 255.0, b: 228.0 / 255.0, a: 1.0 };
-static DARK_ORANGE: Color = Color { r: 186.0 / 255.0, g: 98.0  / 255.0, b: 20.0  / 255.0, a: 1.0 };
+#[allow(clippy::eq_op)]
 static ORANGE: Color      = Color { r: 255.0 / 255.0, g: 138.0 / 255.0, b: 0.0   / 255.0, a: 1.0 };
 static WHITE: Color       = Color { r: 238.0 / 255.0, g: 243.0 / 255.0, b: 244.0 / 255.0, a: 1.0 };
-static VERY_GRAY: Color   = Color { r: 29.0  / 255.0, g: 30.0  / 255.0, b: 32.0  / 255.0, a: 1.0 };
 static DARK_GRAY: Color   = Color { r: 54.0  / 255.0, g: 56.0  / 255.0, b: 49.0  / 255.0, a: 1.0 };
 static LIGHT_GRAY: Color  = Color { r: 76.0  / 255.0, g: 79.0  / 255.0, b: 84.0  / 255.0, a: 1.0 };
-static STONE_GRAY: Color  = Color { r: 67.0  / 255.0, g: 59.0  / 255.0, b: 62.0  / 255.0, a: 1.0 };
 static LIGHT_BROWN: Color = Color { r: 158.0 / 255.0, g: 134.0 / 255.0, b: 100.0 / 255.0, a: 1.0 };
 
 static MONSTER_COLOR: Color = LIGHT_BROWN;
@@ -50,12 +158,627 @@ static TRAP_COLOR: Color = ORANGE;
 
 #[derive(Clone, Debug, PartialEq)]
 enum GameState {
+    MainMenu,
     Playing(usize),
+    Paused(usize),
+    Options(Box<GameState>),
+    Controls(Box<GameState>),
     Lost,
+    GameOver,
     NextLevel(usize),
     Win,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MenuItem {
+    NewRun,
+    Continue,
+    SeededRun,
+    Options,
+    Quit,
+}
+
+const MENU_ITEMS: [MenuItem; 5] =
+    [MenuItem::NewRun, MenuItem::Continue, MenuItem::SeededRun, MenuItem::Options, MenuItem::Quit];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OptionItem {
+    PuzzleMode,
+    MasterVolume,
+    MusicVolume,
+    EffectsVolume,
+    Mute,
+    ScreenEffects,
+    Scaling,
+    Fullscreen,
+    Palette,
+    Intents,
+    Controls,
+    Back,
+}
+
+const OPTION_ITEMS: [OptionItem; 12] =
+    [OptionItem::PuzzleMode, OptionItem::Intents,
+     OptionItem::MasterVolume, OptionItem::MusicVolume, OptionItem::EffectsVolume, OptionItem::Mute,
+     OptionItem::ScreenEffects, OptionItem::Scaling, OptionItem::Fullscreen, OptionItem::Palette,
+     OptionItem::Controls, OptionItem::Back];
+
+/// How the game's screen is fitted to the window. Smooth scaling fills as
+/// much of the window as possible, while pixel perfect only scales by whole
+/// numbers so that every pixel of the art stays the same size.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum ScaleMode {
+    Smooth,
+    PixelPerfect,
+}
+
+impl ScaleMode {
+    fn name(&self) -> &str {
+        return match self {
+            ScaleMode::Smooth => "Smooth",
+            ScaleMode::PixelPerfect => "Pixel Perfect",
+        };
+    }
+
+    fn next(&self) -> ScaleMode {
+        return match self {
+            ScaleMode::Smooth => ScaleMode::PixelPerfect,
+            ScaleMode::PixelPerfect => ScaleMode::Smooth,
+        };
+    }
+}
+
+/// Where the game goes in the current window. The map, HUD and text are laid
+/// out in a WINDOW_WIDTH by WINDOW_HEIGHT area, which is scaled up as far as
+/// the window allows and centered in it. The background fills the rest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Layout {
+    window_size: Vector,
+    scale: f32,
+    // where the top left of the layout lands in the window
+    origin: Vector,
+}
+
+impl Layout {
+    fn new(window_size: Vector, scale_mode: ScaleMode) -> Layout {
+        let layout_size = Vector::new(WINDOW_WIDTH, WINDOW_HEIGHT);
+        let mut scale = (window_size.x / layout_size.x).min(window_size.y / layout_size.y);
+
+        // a window smaller than the layout can only be scaled down smoothly
+        if scale_mode == ScaleMode::PixelPerfect && scale >= 1.0 {
+            scale = scale.floor();
+        }
+
+        return Layout {
+            window_size: window_size,
+            scale: scale,
+            origin: (window_size - layout_size * scale) / 2.0,
+        };
+    }
+
+    /// The part of the layout's coordinates that is visible in the window,
+    /// which is larger than the layout when the window's shape is different
+    fn area(&self) -> Rectangle {
+        return Rectangle::new(-self.origin / self.scale, self.window_size / self.scale);
+    }
+
+    /// The view that draws layout coordinates, and reads the mouse, scaled and centered
+    fn view(&self) -> View {
+        return View::new(self.area());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+}
+
+impl Direction {
+    fn offset(&self) -> Vector {
+        return match self {
+            Direction::Left => Vector::new(-1, 0),
+            Direction::Right => Vector::new(1, 0),
+            Direction::Up => Vector::new(0, -1),
+            Direction::Down => Vector::new(0, 1),
+            Direction::UpLeft => Vector::new(-1, -1),
+            Direction::UpRight => Vector::new(1, -1),
+            Direction::DownLeft => Vector::new(-1, 1),
+            Direction::DownRight => Vector::new(1, 1),
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        return match self {
+            Direction::Left => "Left",
+            Direction::Right => "Right",
+            Direction::Up => "Up",
+            Direction::Down => "Down",
+            Direction::UpLeft => "Up Left",
+            Direction::UpRight => "Up Right",
+            Direction::DownLeft => "Down Left",
+            Direction::DownRight => "Down Right",
+        };
+    }
+}
+
+const DIRECTIONS: [Direction; 8] =
+    [Direction::Left, Direction::Right, Direction::Up, Direction::Down,
+     Direction::UpLeft, Direction::UpRight, Direction::DownLeft, Direction::DownRight];
+
+/// Everything the player can ask the game to do. Input devices are mapped
+/// to actions, and the rest of the game only looks at actions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum Action {
+    Move(Direction),
+    Wait,
+    Interact,
+    UseItem,
+    Undo,
+    Pause,
+    Confirm,
+    Cancel,
+    Restart,
+    ExportStats,
+    ToggleHighScores,
+    ToggleMute,
+    ToggleFullscreen,
+    ScrollLogUp,
+    ScrollLogDown,
+    ExportLog,
+    ToggleIntents,
+    PreviewMove,
+}
+
+impl Action {
+    fn name(&self) -> String {
+        return match self {
+            Action::Move(dir) => format!("Move {}", dir.name()),
+            Action::Wait => "Wait".to_string(),
+            Action::Interact => "Interact".to_string(),
+            Action::UseItem => "Use Item".to_string(),
+            Action::Undo => "Undo".to_string(),
+            Action::Pause => "Pause".to_string(),
+            Action::Confirm => "Confirm".to_string(),
+            Action::Cancel => "Cancel".to_string(),
+            Action::Restart => "Restart".to_string(),
+            Action::ExportStats => "Export Stats".to_string(),
+            Action::ToggleHighScores => "Toggle High Scores".to_string(),
+            Action::ToggleMute => "Toggle Mute".to_string(),
+            Action::ToggleFullscreen => "Toggle Fullscreen".to_string(),
+            Action::ScrollLogUp => "Scroll Log Up".to_string(),
+            Action::ScrollLogDown => "Scroll Log Down".to_string(),
+            Action::ExportLog => "Export Log".to_string(),
+            Action::ToggleIntents => "Toggle Monster Intents".to_string(),
+            Action::PreviewMove => "Preview Move (hold)".to_string(),
+        };
+    }
+}
+
+// actions are checked in order, so only the first action pressed in a frame is used
+const PLAYING_ACTIONS: [Action; 18] =
+    [Action::Pause, Action::Undo, Action::ToggleMute,
+     Action::ScrollLogUp, Action::ScrollLogDown, Action::ExportLog, Action::ToggleIntents,
+     Action::Move(Direction::Left), Action::Move(Direction::Right),
+     Action::Move(Direction::Up), Action::Move(Direction::Down),
+     Action::Move(Direction::UpLeft), Action::Move(Direction::UpRight),
+     Action::Move(Direction::DownLeft), Action::Move(Direction::DownRight),
+     Action::Wait, Action::Interact, Action::UseItem];
+
+const MENU_ACTIONS: [Action; 6] =
+    [Action::Confirm, Action::Cancel,
+     Action::Move(Direction::Up), Action::Move(Direction::Down),
+     Action::Move(Direction::Left), Action::Move(Direction::Right)];
+
+const END_SCREEN_ACTIONS: [Action; 9] =
+    [Action::Confirm, Action::Cancel, Action::Undo,
+     Action::Restart, Action::ExportStats, Action::ToggleHighScores,
+     Action::ScrollLogUp, Action::ScrollLogDown, Action::ExportLog];
+
+/// Keys for each action, in the order actions are listed on the controls screen
+type Bindings = Vec<(Action, Vec<Key>)>;
+
+/// Arrows, WASD, vi keys and the numpad all work out of the box
+fn default_bindings() -> Bindings {
+    return vec![
+        (Action::Move(Direction::Left), vec![Key::Left, Key::A, Key::H, Key::Numpad4]),
+        (Action::Move(Direction::Right), vec![Key::Right, Key::D, Key::L, Key::Numpad6]),
+        (Action::Move(Direction::Up), vec![Key::Up, Key::W, Key::K, Key::Numpad8]),
+        (Action::Move(Direction::Down), vec![Key::Down, Key::S, Key::J, Key::Numpad2]),
+        (Action::Move(Direction::UpLeft), vec![Key::Y, Key::Numpad7]),
+        (Action::Move(Direction::UpRight), vec![Key::U, Key::Numpad9]),
+        (Action::Move(Direction::DownLeft), vec![Key::B, Key::Numpad1]),
+        (Action::Move(Direction::DownRight), vec![Key::N, Key::Numpad3]),
+        (Action::Wait, vec![Key::Period, Key::Numpad5]),
+        (Action::Interact, vec![Key::G]),
+        (Action::UseItem, vec![Key::I]),
+        (Action::Undo, vec![Key::Z, Key::Back]),
+        (Action::Pause, vec![Key::Escape]),
+        (Action::Confirm, vec![Key::Return, Key::Space]),
+        (Action::Cancel, vec![Key::Escape]),
+        (Action::Restart, vec![Key::R]),
+        (Action::ExportStats, vec![Key::E]),
+        (Action::ToggleHighScores, vec![Key::Tab]),
+        (Action::ToggleMute, vec![Key::M]),
+        (Action::ToggleFullscreen, vec![Key::F11]),
+        (Action::ScrollLogUp, vec![Key::PageUp]),
+        (Action::ScrollLogDown, vec![Key::PageDown]),
+        (Action::ExportLog, vec![Key::X]),
+        (Action::ToggleIntents, vec![Key::T]),
+        (Action::PreviewMove, vec![Key::LShift, Key::RShift]),
+    ];
+}
+
+/// Keys that can be bound to actions, and saved by name in the settings file
+const BINDABLE_KEYS: [Key; 82] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::Numpad0, Key::Numpad1, Key::Numpad2, Key::Numpad3, Key::Numpad4,
+    Key::Numpad5, Key::Numpad6, Key::Numpad7, Key::Numpad8, Key::Numpad9,
+    Key::Left, Key::Right, Key::Up, Key::Down,
+    Key::Escape, Key::Return, Key::Space, Key::Back, Key::Tab,
+    Key::Period, Key::Comma, Key::Slash, Key::Semicolon, Key::Minus, Key::Equals,
+    Key::Home, Key::End, Key::PageUp, Key::PageDown, Key::Insert, Key::Delete,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::LShift, Key::RShift, Key::LControl,
+];
+
+fn key_name(key: Key) -> String {
+    return format!("{:?}", key);
+}
+
+fn key_from_name(name: &str) -> Option<Key> {
+    return BINDABLE_KEYS.iter().find(|key| key_name(**key) == name).copied();
+}
+
+fn keys_for(bindings: &Bindings, action: Action) -> Vec<Key> {
+    return bindings.iter()
+                   .find(|(bound_action, _keys)| *bound_action == action)
+                   .map(|(_action, keys)| keys.clone())
+                   .unwrap_or_default();
+}
+
+/// The first of the candidate actions whose key was pressed this frame
+fn poll_action(window: &Window, bindings: &Bindings, candidates: &[Action]) -> Option<Action> {
+    for candidate in candidates.iter() {
+        for (action, keys) in bindings.iter() {
+            if action == candidate &&
+               keys.iter().any(|key| window.keyboard()[*key] == ButtonState::Pressed) {
+                return Some(*candidate);
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Gamepad buttons for each action. Directions also come from the d-pad
+/// and left stick, see gamepad_direction.
+type GamepadBindings = Vec<(Action, Vec<GamepadButton>)>;
+
+fn default_gamepad_bindings() -> GamepadBindings {
+    return vec![
+        (Action::Move(Direction::Left), vec![GamepadButton::DpadLeft]),
+        (Action::Move(Direction::Right), vec![GamepadButton::DpadRight]),
+        (Action::Move(Direction::Up), vec![GamepadButton::DpadUp]),
+        (Action::Move(Direction::Down), vec![GamepadButton::DpadDown]),
+        (Action::Wait, vec![GamepadButton::FaceLeft]),
+        (Action::Interact, vec![GamepadButton::FaceUp]),
+        (Action::UseItem, vec![GamepadButton::ShoulderRight]),
+        (Action::Undo, vec![GamepadButton::ShoulderLeft]),
+        (Action::Pause, vec![GamepadButton::Start]),
+        (Action::Confirm, vec![GamepadButton::FaceDown]),
+        (Action::Cancel, vec![GamepadButton::FaceRight, GamepadButton::Start]),
+        (Action::Restart, vec![GamepadButton::Select]),
+        (Action::ExportStats, vec![GamepadButton::FaceUp]),
+        (Action::ToggleHighScores, vec![GamepadButton::FaceLeft]),
+        (Action::PreviewMove, vec![GamepadButton::TriggerLeft]),
+    ];
+}
+
+/// Button names as printed on a typical controller
+fn gamepad_button_name(button: GamepadButton) -> &'static str {
+    return match button {
+        GamepadButton::FaceDown => "A",
+        GamepadButton::FaceRight => "B",
+        GamepadButton::FaceLeft => "X",
+        GamepadButton::FaceUp => "Y",
+        GamepadButton::ShoulderLeft => "LB",
+        GamepadButton::ShoulderRight => "RB",
+        GamepadButton::TriggerLeft => "LT",
+        GamepadButton::TriggerRight => "RT",
+        GamepadButton::Select => "Select",
+        GamepadButton::Start => "Start",
+        GamepadButton::StickButtonLeft => "L3",
+        GamepadButton::StickButtonRight => "R3",
+        GamepadButton::DpadUp => "D-pad Up",
+        GamepadButton::DpadDown => "D-pad Down",
+        GamepadButton::DpadLeft => "D-pad Left",
+        GamepadButton::DpadRight => "D-pad Right",
+        GamepadButton::Home => "Home",
+    };
+}
+
+fn gamepad_buttons_for(bindings: &GamepadBindings, action: Action) -> Vec<GamepadButton> {
+    return bindings.iter()
+                   .find(|(bound_action, _buttons)| *bound_action == action)
+                   .map(|(_action, buttons)| buttons.clone())
+                   .unwrap_or_default();
+}
+
+/// The first of the candidate actions whose button was pressed this frame on any gamepad.
+/// Directions are left to gamepad_direction so that they repeat while held.
+fn poll_gamepad(window: &Window, bindings: &GamepadBindings, candidates: &[Action]) -> Option<Action> {
+    for candidate in candidates.iter() {
+        if let Action::Move(_) = candidate {
+            continue;
+        }
+
+        for (action, buttons) in bindings.iter() {
+            if action == candidate &&
+               window.gamepads().iter().any(|pad| buttons.iter().any(|button| pad[*button] == ButtonState::Pressed)) {
+                return Some(*candidate);
+            }
+        }
+    }
+
+    return None;
+}
+
+/// The direction held on the d-pad or left stick of any gamepad
+fn gamepad_direction(window: &Window) -> Option<Direction> {
+    for pad in window.gamepads().iter() {
+        let mut x = 0;
+        let mut y = 0;
+        if pad[GamepadButton::DpadLeft].is_down() { x -= 1; }
+        if pad[GamepadButton::DpadRight].is_down() { x += 1; }
+        if pad[GamepadButton::DpadUp].is_down() { y -= 1; }
+        if pad[GamepadButton::DpadDown].is_down() { y += 1; }
+
+        if x == 0 && y == 0 {
+            let stick = Vector::new(pad[GamepadAxis::LeftStickX], pad[GamepadAxis::LeftStickY]);
+            if magnitude(stick) >= STICK_DEAD_ZONE {
+                // split the stick into eight 45 degree sectors, with up being negative
+                // y to match the map. mostly horizontal or vertical stays straight.
+                let sector_edge = (22.5f32).to_radians().tan();
+                if stick.x.abs() > sector_edge * stick.y.abs() {
+                    x = stick.x.signum() as i32;
+                }
+                if stick.y.abs() > sector_edge * stick.x.abs() {
+                    y = stick.y.signum() as i32;
+                }
+            }
+        }
+
+        let dir = DIRECTIONS.iter().find(|dir| dir.offset() == Vector::new(x, y));
+        if dir.is_some() {
+            return dir.copied();
+        }
+    }
+
+    return None;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InputDevice {
+    Keyboard,
+    Gamepad,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Binding {
+    action: Action,
+    keys: Vec<String>,
+}
+
+/// Settings saved between runs. Missing fields take their default values,
+/// so older settings files still load.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    puzzle_mode: bool,
+    bindings: Vec<Binding>,
+    master_volume: u8,
+    music_volume: u8,
+    effects_volume: u8,
+    muted: bool,
+    effects: bool,
+    scale_mode: ScaleMode,
+    fullscreen: bool,
+    palette: String,
+    show_intents: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            puzzle_mode: false,
+            bindings: bindings_to_config(&default_bindings()),
+            master_volume: 80,
+            music_volume: 60,
+            effects_volume: 80,
+            muted: false,
+            effects: true,
+            scale_mode: ScaleMode::Smooth,
+            fullscreen: false,
+            palette: "Default".to_string(),
+            show_intents: false,
+        };
+    }
+}
+
+fn bindings_to_config(bindings: &Bindings) -> Vec<Binding> {
+    return bindings.iter().map(|(action, keys)| {
+        Binding {
+            action: *action,
+            keys: keys.iter().map(|key| key_name(*key)).collect(),
+        }
+    }).collect();
+}
+
+/// Saved bindings replace the defaults action by action, so actions added
+/// after the settings were saved keep their default keys.
+fn bindings_from_config(config: &Config) -> Bindings {
+    let mut bindings = default_bindings();
+    for binding in config.bindings.iter() {
+        if let Some(entry) = bindings.iter_mut().find(|(action, _keys)| *action == binding.action) {
+            let keys: Vec<Key> = binding.keys.iter().filter_map(|name| key_from_name(name)).collect();
+
+            // a binding whose keys can't be read, say from a hand edited file,
+            // keeps the default keys rather than leaving the action unbound
+            if keys.is_empty() && !binding.keys.is_empty() {
+                continue;
+            }
+            entry.1 = keys;
+        }
+    }
+
+    return bindings;
+}
+
+fn load_config() -> Config {
+    return saving::load::<Config>(APP_NAME, CONFIG_PROFILE).unwrap_or_default();
+}
+
+/// Colors for everything drawn in the game. Themes for color blindness
+/// and high contrast also turn on outlines, which mark traps with a square
+/// frame and monsters with a round dot so they can be told apart by shape.
+#[derive(Clone, Debug, PartialEq)]
+struct Palette {
+    name: String,
+    outlines: bool,
+    background: Color,
+    text: Color,
+    text_disabled: Color,
+    floor_dark: Color,
+    floor_light: Color,
+    wall: Color,
+    player: Color,
+    monster: Color,
+    trap: Color,
+    exit: Color,
+    hurt: Color,
+    health: Color,
+    dust: Color,
+    sparkle: Color,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        return Palette {
+            name: "Default".to_string(),
+            outlines: false,
+            background: BACKGROUND_COLOR,
+            text: WHITE,
+            text_disabled: LIGHT_GRAY,
+            floor_dark: DARK_GRAY,
+            floor_light: LIGHT_GRAY,
+            wall: LIGHT_GRAY,
+            player: WHITE,
+            monster: MONSTER_COLOR,
+            trap: TRAP_COLOR,
+            exit: WHITE,
+            hurt: RED,
+            health: Color::RED,
+            dust: LIGHT_BROWN,
+            sparkle: BRIGHT_BLUE,
+        };
+    }
+}
+
+/// A palette as written in its file. Colors are given as "#rrggbb",
+/// and any that are left out keep their default.
+#[derive(Clone, Debug, Deserialize)]
+struct PaletteFile {
+    name: String,
+    #[serde(default)]
+    outlines: bool,
+    colors: BTreeMap<String, String>,
+}
+
+impl Palette {
+    fn from_file(file: PaletteFile) -> std::result::Result<Palette, String> {
+        let mut palette = Palette {
+            name: file.name,
+            outlines: file.outlines,
+            ..Palette::default()
+        };
+
+        for (name, hex) in file.colors.iter() {
+            let color = color_from_hex(hex).ok_or(format!("'{}' is not a color", hex))?;
+            let field =
+                match name.as_str() {
+                    "background" => &mut palette.background,
+                    "text" => &mut palette.text,
+                    "text_disabled" => &mut palette.text_disabled,
+                    "floor_dark" => &mut palette.floor_dark,
+                    "floor_light" => &mut palette.floor_light,
+                    "wall" => &mut palette.wall,
+                    "player" => &mut palette.player,
+                    "monster" => &mut palette.monster,
+                    "trap" => &mut palette.trap,
+                    "exit" => &mut palette.exit,
+                    "hurt" => &mut palette.hurt,
+                    "health" => &mut palette.health,
+                    "dust" => &mut palette.dust,
+                    "sparkle" => &mut palette.sparkle,
+                    _ => return Err(format!("unknown color '{}'", name)),
+                };
+            *field = color;
+        }
+
+        return Ok(palette);
+    }
+
+    fn entity_color(&self, entity: &Entity) -> Color {
+        return match entity.typ {
+            EntityType::Player(_) => self.player,
+            EntityType::Monster(_) => self.monster,
+            EntityType::Trap(Trap::NextLevel) | EntityType::Trap(Trap::Win) => self.exit,
+            EntityType::Trap(_) => self.trap,
+        };
+    }
+}
+
+fn color_from_hex(hex: &str) -> Option<Color> {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+
+    let channel = |index: usize| {
+        u8::from_str_radix(hex.get(index..index + 2)?, 16).ok().map(|value| value as f32 / 255.0)
+    };
+    return Some(Color { r: channel(0)?, g: channel(2)?, b: channel(4)?, a: 1.0 });
+}
+
+/// Load every palette that can be read. A broken palette file is reported
+/// and left out rather than stopping the game.
+fn load_palettes() -> impl Future<Item = Vec<Palette>, Error = quicksilver::Error> {
+    let files = PALETTE_FILES.iter().map(|file_name| load_file(*file_name)).collect::<Vec<_>>();
+    return join_all(files).map(|files| {
+        let mut palettes = Vec::new();
+        for (file_name, bytes) in PALETTE_FILES.iter().zip(files.iter()) {
+            let palette =
+                serde_json::from_slice::<PaletteFile>(bytes).map_err(|err| err.to_string())
+                                                           .and_then(Palette::from_file);
+            match palette {
+                Ok(palette) => palettes.push(palette),
+                Err(err) => eprintln!("Could not read {}: {}", file_name, err),
+            }
+        }
+
+        return palettes;
+    });
+}
+
 #[derive(Clone, Debug, PartialEq)]
 struct Tile {
     pos: Vector,
@@ -80,6 +803,15 @@ enum Status {
     Berserk,
 }
 
+impl Status {
+    /// Shown above anything with the status
+    fn icon(&self) -> &'static str {
+        return match self {
+            Status::Berserk => "!",
+        };
+    }
+}
+
 type Hp = i32;
 
 type EntityId = usize;
@@ -104,12 +836,49 @@ enum Trap {
     Win,
 }
 
+impl Trap {
+    fn name(&self) -> &'static str {
+        return match self {
+            Trap::Berserk => "Berserk",
+            Trap::Kill => "Kill",
+            Trap::Bump => "Bump",
+            Trap::Teleport => "Teleport",
+            Trap::CountDown(_) => "Count Down",
+            Trap::Arrow(_) => "Arrow",
+            Trap::NextLevel => "Next Level",
+            Trap::Win => "Win",
+        };
+    }
+
+    fn description(&self) -> String {
+        return match self {
+            Trap::Berserk => "Berserk trap: doubles the speed of whatever steps on it".to_string(),
+            Trap::Kill => "Kill trap: deals 5 damage, then breaks".to_string(),
+            Trap::Bump => "Bump trap: knocks you onto a random nearby tile".to_string(),
+            Trap::Teleport => "Teleport trap: sends you to the next teleporter".to_string(),
+            Trap::CountDown(n) => format!("Count Down trap: deals 5 damage after {} more triggers", n),
+            Trap::Arrow(arrow) => format!("Arrow trap: slides you {:?} until something stops you", arrow),
+            Trap::NextLevel => "Stairs: down to the next level".to_string(),
+            Trap::Win => "Exit: leave the dungeon and win".to_string(),
+        };
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum MonsterType {
     Gol,
     Rook,
 }
 
+impl MonsterType {
+    fn name(&self) -> &'static str {
+        return match self {
+            MonsterType::Gol => "Gol",
+            MonsterType::Rook => "Rook",
+        };
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 struct Monster {
     hp: Hp,
@@ -201,7 +970,7 @@ impl HasHp for Player {
     }
 
     fn hp(&self) -> Hp {
-        return self.max_hp;
+        return self.hp;
     }
 }
 
@@ -211,23 +980,23 @@ impl HasHp for Monster {
     }
 
     fn hp(&self) -> Hp {
-        return self.max_hp;
+        return self.hp;
     }
 }
 
 impl HasHp for Entity {
     fn max_hp(&self) -> Hp {
         return match &self.typ {
-            EntityType::Monster(monster) => monster.max_hp,
-            EntityType::Player(player) => player.max_hp,
+            EntityType::Monster(monster) => monster.max_hp(),
+            EntityType::Player(player) => player.max_hp(),
             _ => panic!("Tried to get hp from entity with no HP!"),
         };
     }
 
     fn hp(&self) -> Hp {
         return match &self.typ {
-            EntityType::Monster(monster) => monster.hp,
-            EntityType::Player(player) => player.hp,
+            EntityType::Monster(monster) => monster.hp(),
+            EntityType::Player(player) => player.hp(),
             _ => panic!("Tried to get hp from entity with no HP!"),
         };
     }
@@ -237,1495 +1006,4949 @@ type Map = Vec<Tile>;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum Animation {
-    MonsterAttack(MonsterType, Vector, usize),
-    MonsterDeath(MonsterType, Vector, usize),
+    MonsterAttack(MonsterType, Vector, Arrow, f64),
+    MonsterDeath(MonsterType, Vector, f64),
+    PlayerDeath(Vector, f64),
 }
 
-fn generate_map(size: Vector) -> Vec<Tile> {
-    let width = size.x as usize;
-    let height = size.y as usize;
-    let mut map = Vec::with_capacity(width * height);
-    for x in 0..width {
-        for y in 0..height {
-            let mut tile = Tile::wall(x, y);
+/// What dealt damage or killed something
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cause {
+    Attack(EntityType),
+    Trap(Trap),
+}
 
-            if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
-                tile.glyph = WALL_CHAR;
-                tile.blocks = true;
-            };
-            map.push(tile);
-        }
+impl Cause {
+    /// Where damage came from, in the run statistics
+    fn damage_source(&self) -> String {
+        return match self {
+            Cause::Attack(EntityType::Monster(monster)) => monster.typ.name().to_string(),
+            Cause::Attack(_) => "Player".to_string(),
+            Cause::Trap(trap) => format!("{} trap", trap.name()),
+        };
     }
 
-    let mut rng = thread_rng();
-    let mut walls_placed = 0;
-    while walls_placed < 5 {
-        let mut x = rng.gen_range(2 as i32, MAP_WIDTH as i32);
-        let mut y = rng.gen_range(2 as i32, MAP_HEIGHT as i32);
-        let x_dir: i32 = rng.gen_range(-1, 2);
-        let y_dir: i32 = rng.gen_range(-1, 2);
-        let dist = rng.gen_range(1, 5);
-
-        if x_dir.abs() == y_dir.abs() {
-            continue;
-        }
-
-        for _square_index in 0..dist {
-            if let Some(map_index) = map.iter().position(|tile| tile.pos.x == x as f32 && tile.pos.y == y as f32) {
-                map[map_index] = Tile::wall(x as usize, y as usize);
-                map[map_index].glyph = WALL_CHAR;
-                map[map_index].blocks = true;
-                x += x_dir;
-                y += y_dir;
-                if x < 0 || x >= MAP_WIDTH as i32 || y < 0 || y > MAP_HEIGHT as i32 {
-                    break;
-                }
-            }
-        }
-
-        if rng.gen_range(0.0, 1.0) > 0.5 {
-            let x_dir = x_dir * -1;
-            let y_dir = y_dir * -1;
-            x += x_dir;
-            y += y_dir;
-            if let Some(map_index) = map.iter().position(|tile| tile.pos.x == x as f32 && tile.pos.y == y as f32) {
-                map[map_index] = Tile::wall(x as usize, y as usize);
-                map[map_index].glyph = WALL_CHAR;
-                map[map_index].blocks = true;
-            }
-        }
-
-        walls_placed += 1;
+    /// How something was killed, in the run statistics
+    fn kill_source(&self) -> &'static str {
+        return match self {
+            Cause::Attack(_) => "combat",
+            Cause::Trap(trap) => trap.name(),
+        };
     }
-
-    return map;
 }
 
-fn blocked_tile(pos: Vector, map: &Map) -> bool {
-    return map.iter().any(|tile| tile.blocks && tile.pos == pos);
+/// A trap going off during a turn
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct TrapTrigger {
+    trap: Trap,
+    // what stepped on the trap
+    typ: EntityType,
+    pos: Vector,
+    // where the trap sent the entity, such as the other end of a teleport
+    destination: Vector,
+    // seconds until the entity is shown reaching the trap
+    delay: f64,
 }
 
-fn occupied_tile(pos: Vector, entities: &Vec<Entity>) -> Option<Entity> {
-    return entities.iter().find(|entity| entity.pos == pos).map(|entity| entity.clone());
+/// Something that happened during a turn. The rules only report what
+/// happened, and everything else, such as sound, animations, the message
+/// log and the run statistics, listens for these events.
+///
+/// Entities are described by their type and position at the time, since
+/// their index can change once dead monsters and used traps are removed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GameEvent {
+    Moved { typ: EntityType, from: Vector, to: Vector },
+    Attacked { attacker: EntityType, pos: Vector, target: EntityType, direction: Arrow },
+    Damaged { typ: EntityType, pos: Vector, amount: Hp, cause: Cause },
+    TrapTriggered(TrapTrigger),
+    Died { typ: EntityType, pos: Vector, cause: Cause },
+    LevelCleared(usize),
 }
 
-fn trap_tile(pos: Vector, entities: &Vec<Entity>) -> Option<Entity> {
-    return entities.iter().find(|entity| entity.typ.is_trap() && entity.pos == pos).map(|entity| entity.clone());
+/// Anything that reacts to events, such as the audio or the message log
+trait EventListener {
+    fn on_event(&mut self, event: &GameEvent);
 }
 
-fn magnitude(vec: Vector) -> f32 {
-    return (vec.x.powi(2) + vec.y.powi(2)).sqrt();
+/// Damage an entity, reporting the damage and, if it was fatal, the death
+fn deal_damage(entity: &mut Entity, amount: Hp, cause: Cause, events: &mut Vec<GameEvent>) {
+    let was_alive = entity.hp() > 0;
+    entity.typ.lose_hp(amount);
+    events.push(GameEvent::Damaged { typ: entity.typ, pos: entity.pos, amount: amount, cause: cause });
+
+    if was_alive && entity.hp() <= 0 {
+        events.push(GameEvent::Died { typ: entity.typ, pos: entity.pos, cause: cause });
+    }
 }
 
-fn clamp(min: f32, max: f32, value: f32) -> f32 {
-    let result: f32;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SoundEffect {
+    Footstep,
+    MonsterAttack,
+    MonsterDeath,
+    PlayerDeath,
+    LevelStart,
+    TrapBerserk,
+    TrapKill,
+    TrapBump,
+    TrapTeleport,
+    TrapCountDown,
+    TrapArrow,
+    TrapNextLevel,
+    TrapWin,
+}
 
-    if value < min {
-        result = min;
-    } else if value > max {
-        result = max;
-    } else {
-        result = value;
+const SOUND_EFFECTS: [SoundEffect; 13] =
+    [SoundEffect::Footstep, SoundEffect::MonsterAttack, SoundEffect::MonsterDeath,
+     SoundEffect::PlayerDeath, SoundEffect::LevelStart,
+     SoundEffect::TrapBerserk, SoundEffect::TrapKill, SoundEffect::TrapBump, SoundEffect::TrapTeleport,
+     SoundEffect::TrapCountDown, SoundEffect::TrapArrow, SoundEffect::TrapNextLevel, SoundEffect::TrapWin];
+
+impl SoundEffect {
+    fn trap(trap: Trap) -> SoundEffect {
+        return match trap {
+            Trap::Berserk => SoundEffect::TrapBerserk,
+            Trap::Kill => SoundEffect::TrapKill,
+            Trap::Bump => SoundEffect::TrapBump,
+            Trap::Teleport => SoundEffect::TrapTeleport,
+            Trap::CountDown(_) => SoundEffect::TrapCountDown,
+            Trap::Arrow(_) => SoundEffect::TrapArrow,
+            Trap::NextLevel => SoundEffect::TrapNextLevel,
+            Trap::Win => SoundEffect::TrapWin,
+        };
     }
 
-    return result;
+    fn file_name(&self) -> &'static str {
+        return match self {
+            SoundEffect::Footstep => "sounds/footstep.wav",
+            SoundEffect::MonsterAttack => "sounds/monster_attack.wav",
+            SoundEffect::MonsterDeath => "sounds/monster_death.wav",
+            SoundEffect::PlayerDeath => "sounds/player_death.wav",
+            SoundEffect::LevelStart => "sounds/level_start.wav",
+            SoundEffect::TrapBerserk => "sounds/trap_berserk.wav",
+            SoundEffect::TrapKill => "sounds/trap_kill.wav",
+            SoundEffect::TrapBump => "sounds/trap_bump.wav",
+            SoundEffect::TrapTeleport => "sounds/trap_teleport.wav",
+            SoundEffect::TrapCountDown => "sounds/trap_count_down.wav",
+            SoundEffect::TrapArrow => "sounds/trap_arrow.wav",
+            SoundEffect::TrapNextLevel => "sounds/trap_next_level.wav",
+            SoundEffect::TrapWin => "sounds/trap_win.wav",
+        };
+    }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum AnimState {
-    None,
-    Idle(usize),
-    Attacking(usize, Arrow),
+/// Sound effects and music. The game queues effects as things happen,
+/// and they are played on the next update.
+///
+/// Quicksilver sounds cannot be stopped once playing, so muting or changing
+/// level only stops the next sound from starting. Music tracks are kept
+/// short so that this is not noticeable.
+struct Audio {
+    effects: HashMap<SoundEffect, Asset<Sound>>,
+    music: Vec<Asset<Sound>>,
+    queued: Vec<SoundEffect>,
+    music_track: Option<usize>,
+    music_time: f64,
+    master_volume: u8,
+    music_volume: u8,
+    effects_volume: u8,
+    muted: bool,
 }
 
-impl AnimState {
-    fn is_none(&self) -> bool {
-        match self {
-            AnimState::None => true,
-            AnimState::Idle(_) => false,
-            AnimState::Attacking(_, _) => false,
+impl Audio {
+    fn new(config: &Config) -> Audio {
+        let mut effects = HashMap::new();
+        for effect in SOUND_EFFECTS.iter() {
+            effects.insert(*effect, Asset::new(Sound::load(effect.file_name())));
         }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-struct Entity {
-    last_pos: Vector,
-    pos: Vector,
-    glyph: char,
-    color: Color,
-    typ: EntityType,
-    anim_state: AnimState,
-}
 
-impl Entity {
-    fn trap(pos: Vector, trap: Trap) -> Entity {
-        let color = match trap {
-            Trap::NextLevel => WHITE,
-            Trap::Win => WHITE,
-            _ => TRAP_COLOR,
+        // one track for each level
+        let music = (0..=NUM_LEVEL_GAME).map(|level| {
+            Asset::new(Sound::load(format!("sounds/music_{}.wav", level)))
+        }).collect();
+
+        return Audio {
+            effects: effects,
+            music: music,
+            queued: Vec::new(),
+            music_track: None,
+            music_time: 0.0,
+            master_volume: config.master_volume,
+            music_volume: config.music_volume,
+            effects_volume: config.effects_volume,
+            muted: config.muted,
         };
+    }
 
-        let chr = match trap {
-            Trap::Kill => 147 as char,
-            Trap::Berserk => '*',
-            Trap::Bump => 42 as char,
-            Trap::Teleport => '!',
-            Trap::CountDown(n) => ('0' as u8 + n) as char,
-            Trap::Arrow(dir) => {
-                match dir {
-                    Arrow::Left => 17 as char,
-                    Arrow::Right => 16 as char,
-                    Arrow::Up => 18 as char,
-                    Arrow::Down => 19 as char,
-                }
-            }
-            Trap::NextLevel => 3 as char,
-            Trap::Win => 255 as char,
-        };
+    fn volume(&self, channel_volume: u8) -> f32 {
+        return (self.master_volume as f32 / 100.0) * (channel_volume as f32 / 100.0);
+    }
 
-        let anim_state =
-            match trap {
-                Trap::Arrow(_) | Trap::NextLevel | Trap::Berserk | Trap::CountDown(_) => AnimState::None,
-                _ => AnimState::Idle(0),
-            };
+    fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+    }
 
-        Entity {
-            last_pos: pos,
-            pos: pos,
-            glyph: chr,
-            color: color,
-            typ: EntityType::trap(trap),
-            anim_state: anim_state,
+    /// Play queued effects and keep the music for the given level going.
+    /// No level means no music, such as in the main menu.
+    fn update(&mut self, level: Option<usize>, dt: f64) {
+        let queued: Vec<SoundEffect> = self.queued.drain(..).collect();
+
+        // the current track keeps playing while muted, so keep timing it and
+        // only start music again once it would have finished
+        self.music_time += dt;
+        if self.muted {
+            if self.music_time >= MUSIC_TRACK_SECONDS {
+                self.music_track = None;
+                self.music_time = 0.0;
+            }
+            return;
         }
-    }
 
-    fn gol(pos: Vector) -> Entity {
-        Entity {
-            last_pos: pos,
-            pos: pos,
-            glyph: 152 as char,
-            color: MONSTER_COLOR,
-            typ: EntityType::monster(1, MonsterType::Gol),
-            anim_state: AnimState::Idle(0),
+        let effects_volume = self.volume(self.effects_volume);
+        for effect in queued.iter() {
+            if let Some(sound) = self.effects.get_mut(effect) {
+                // a missing sound or audio device should not stop the game
+                let _ = sound.execute(|sound| {
+                    sound.set_volume(effects_volume);
+                    return sound.play();
+                });
+            }
         }
-    }
 
-    fn rook(pos: Vector) -> Entity {
-        Entity {
-            last_pos: pos,
-            pos: pos,
-            glyph: 130 as char,
-            color: MONSTER_COLOR,
-            typ: EntityType::monster(2, MonsterType::Rook),
-            anim_state: AnimState::Idle(0),
+        let track = level.map(|level| level % self.music.len());
+        if track != self.music_track || self.music_time >= MUSIC_TRACK_SECONDS {
+            self.music_track = track;
+            self.music_time = 0.0;
+
+            if let Some(track) = track {
+                let music_volume = self.volume(self.music_volume);
+                let _ = self.music[track].execute(|sound| {
+                    sound.set_volume(music_volume);
+                    return sound.play();
+                });
+            }
         }
     }
 }
 
-fn map_pos<R: Rng>(rng: &mut R) -> Vector {
-    return Vector::new(rng.gen_range(1, MAP_WIDTH as u16 - 1),
-                       rng.gen_range(1, MAP_HEIGHT as u16 - 1));
-}
-
-fn map_unique_pos(map: Map) -> impl Iterator<Item=Vector> {
-    let mut rng = thread_rng();
-    let mut positions: Vec<Vector> = Vec::new();
-    return std::iter::from_fn(move || {
-        let mut new_pos = map_pos(&mut rng);
-        while positions.iter().find(|pos| **pos == new_pos).is_some() ||
-              map[new_pos.y as usize + new_pos.x as usize * MAP_HEIGHT].blocks {
-            new_pos = map_pos(&mut rng);
-        }
-
-        positions.push(new_pos);
+impl EventListener for Audio {
+    fn on_event(&mut self, event: &GameEvent) {
+        let effect =
+            match event {
+                GameEvent::Moved { typ: EntityType::Player(_), .. } => SoundEffect::Footstep,
+                GameEvent::Attacked { attacker: EntityType::Monster(_), .. } => SoundEffect::MonsterAttack,
+                GameEvent::TrapTriggered(trigger) => SoundEffect::trap(trigger.trap),
+                GameEvent::Died { typ: EntityType::Player(_), .. } => SoundEffect::PlayerDeath,
+                GameEvent::Died { typ: EntityType::Monster(_), .. } => SoundEffect::MonsterDeath,
+                _ => return,
+            };
 
-        return Some(new_pos);
-    });
+        self.queued.push(effect);
+    }
 }
 
-fn generate_entities(last_level: bool, entities: &mut Vec<Entity>, map: &Map) -> Vector {
-    let player_pos;
-
-    if false {
-        entities.push(Entity::gol(Vector::new(4, 4)));
-        entities.push(Entity::rook(Vector::new(2, 1)));
-        entities.push(Entity::trap(Vector::new(6, 6), Trap::Bump)); 
-        entities.push(Entity::trap(Vector::new(3, 8), Trap::Berserk)); 
-        entities.push(Entity::trap(Vector::new(4, 2), Trap::Arrow(Arrow::Left))); 
-        entities.push(Entity::trap(Vector::new(4, 3), Trap::Arrow(Arrow::Left))); 
-        entities.push(Entity::trap(Vector::new(4, 4), Trap::Arrow(Arrow::Left))); 
-        entities.push(Entity::trap(Vector::new(7, 6), Trap::Kill)); 
-        entities.push(Entity::trap(Vector::new(7, 7), Trap::Kill)); 
-        entities.push(Entity::trap(Vector::new(7, 2), Trap::Teleport)); 
-        entities.push(Entity::trap(Vector::new(4, 8), Trap::Teleport)); 
-        entities.push(Entity::trap(Vector::new(1, 2), Trap::CountDown(3))); 
-        entities.push(Entity::trap(Vector::new(8, 8), Trap::NextLevel));
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Effect {
+    Shake,
+    Flash,
+    Dust(Vector),
+    Sparkle(Vector),
+}
 
-        player_pos = Vector::new(3, 4);
-    } else {
-        let mut positions = map_unique_pos(map.clone());
+/// A short lived speck drawn over the map. Positions and velocities are in tiles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Particle {
+    pos: Vector,
+    velocity: Vector,
+    age: f64,
+    life: f64,
+    color: Color,
+    twinkle: bool,
+}
 
-        entities.push(Entity::gol(positions.next().unwrap()));
-        entities.push(Entity::rook(positions.next().unwrap()));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Bump));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Teleport));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Teleport));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Left)));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Right)));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Up)));
-        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Down)));
+impl Particle {
+    fn burst(pos: Vector, count: usize, speed: (f32, f32), colors: &[Color], twinkle: bool) -> Vec<Particle> {
+        let mut rng = thread_rng();
+        return (0..count).map(|index| {
+            let angle = rng.gen_range(0.0, 2.0 * std::f32::consts::PI);
+            let speed = rng.gen_range(speed.0, speed.1);
+            Particle {
+                pos: pos,
+                velocity: Vector::new(angle.cos() * speed, angle.sin() * speed),
+                age: 0.0,
+                life: rng.gen_range(PARTICLE_LIFE * 0.5, PARTICLE_LIFE),
+                color: colors[index % colors.len()],
+                twinkle: twinkle,
+            }
+        }).collect();
+    }
 
-        if last_level {
-            entities.push(Entity::trap(positions.next().unwrap(), Trap::Win));
-        } else {
-            entities.push(Entity::trap(positions.next().unwrap(), Trap::NextLevel));
+    fn alpha(&self) -> f32 {
+        let fade = 1.0 - (self.age / self.life) as f32;
+        if self.twinkle {
+            return fade * (0.5 + 0.5 * (self.age as f32 * 40.0).sin());
         }
-
-        player_pos = positions.next().unwrap();
+        return fade;
     }
-
-    return player_pos;
 }
 
-struct Game {
-    game_state: GameState,
-    title: Asset<Image>,
-    mononoki_font_info: Asset<Image>,
-    square_font_info: Asset<Image>,
-    lost_game_message: Asset<Image>,
-    win_game_message: Asset<Image>,
-    char_map: Asset<HashMap<u32, Image>>,
-    inventory: Asset<Image>,
-    map_size: Vector,
-    map: Map,
-    entities: Vec<Entity>,
-    player_id: usize,
-    tileset: Asset<HashMap<char, Image>>,
-    noise: Perlin,
-    gol_idle: Asset<Vec<Image>>,
-    rook_idle: Asset<Vec<Image>>,
-    player_idle: Asset<Vec<Image>>,
-    gol_attack_up: Asset<Vec<Image>>,
-    gol_attack_down: Asset<Vec<Image>>,
-    gol_attack_left: Asset<Vec<Image>>,
-    gol_attack_right: Asset<Vec<Image>>,
-    rook_attack_down: Asset<Vec<Image>>,
-    rook_attack_up: Asset<Vec<Image>>,
-    rook_attack_right: Asset<Vec<Image>>,
-    rook_attack_left: Asset<Vec<Image>>,
-    trap_win: Asset<Vec<Image>>,
-    trap_damage: Asset<Vec<Image>>,
-    trap_arrow_up: Asset<Vec<Image>>,
-    trap_arrow_down: Asset<Vec<Image>>,
-    trap_arrow_right: Asset<Vec<Image>>,
-    trap_arrow_left: Asset<Vec<Image>>,
-    trap_random_direction: Asset<Vec<Image>>,
-    trap_tele: Asset<Vec<Image>>,
-    gol_death: Asset<Vec<Image>>,
-    rook_death: Asset<Vec<Image>>,
-    time_passed: f64,
-    animations: Vec<Animation>,
+/// Screen shake, screen flashes and particles. These are only feedback, so
+/// they use their own random numbers and never touch the game's rng.
+///
+/// Effects are scheduled with a delay so that they line up with the
+/// entity reaching the trap on screen rather than the turn it happened on.
+struct Effects {
+    enabled: bool,
+    scheduled: Vec<(f64, Effect)>,
+    shake_time: f64,
+    flash_time: f64,
+    particles: Vec<Particle>,
 }
 
-impl State for Game {
-    /// Load the assets and initialise the game
-    fn new() -> Result<Self> {
-        // The Mononoki font: https://madmalik.github.io/mononoki/
-        // License: SIL Open Font License 1.1
-        let font_mononoki = "mononoki-Regular.ttf";
-
-        let title = Asset::new(Font::load(font_mononoki).and_then(|font| {
-            font.render("Stone Fall", &FontStyle::new(72.0, WHITE))
-        }));
-
-        let font_image = "LD45_SpriteSheet.png";
-        let char_map = Asset::new(Image::load(font_image).and_then(|image| {
-            let mut char_map = HashMap::new();
-            let char_size = Vector::new(16, 16);
-            for char_ix in 0..256 {
-                let char_x = char_ix % 16;
-                let char_y = char_ix / 16;
-                let char_pos = Vector::new(char_x * 16, char_y * 16);
-                char_map.insert(char_ix,
-                                image.subimage(Rectangle::new(char_pos, char_size)));
-            }
+impl Effects {
+    fn new(config: &Config) -> Effects {
+        return Effects {
+            enabled: config.effects,
+            scheduled: Vec::new(),
+            shake_time: 0.0,
+            flash_time: 0.0,
+            particles: Vec::new(),
+        };
+    }
 
-            return Ok(char_map);
-        }));
+    fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        self.clear();
+    }
 
-        let gol_idle_name = "Gol_Idle.png";
-        let gol_idle = Asset::new(Image::load(gol_idle_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_idle = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_idle.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    fn clear(&mut self) {
+        self.scheduled.clear();
+        self.shake_time = 0.0;
+        self.flash_time = 0.0;
+        self.particles.clear();
+    }
 
-            return Ok(gol_idle);
-        }));
+    fn schedule(&mut self, delay: f64, effect: Effect) {
+        if self.enabled {
+            self.scheduled.push((delay, effect));
+        }
+    }
 
-        let rook_idle_name = "Rook_Idle.png";
-        let rook_idle = Asset::new(Image::load(rook_idle_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_idle = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_idle.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    fn trap_triggered(&mut self, trigger: &TrapTrigger) {
+        match trigger.trap {
+            Trap::Kill => self.schedule(trigger.delay, Effect::Shake),
 
-            return Ok(rook_idle);
-        }));
+            Trap::Bump => self.schedule(trigger.delay, Effect::Dust(trigger.pos)),
 
-        let gol_attack_name = "Gol_AttackUp.png";
-        let gol_attack_up = Asset::new(Image::load(gol_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+            // sparkle as the entity fades out, and again as it fades in
+            Trap::Teleport => {
+                self.schedule(trigger.delay, Effect::Sparkle(trigger.pos));
+                self.schedule(trigger.delay + TELEPORT_TIME / 2.0, Effect::Sparkle(trigger.destination));
+            },
 
-            return Ok(gol_attack);
-        }));
+            _ => (),
+        }
+    }
 
-        let gol_attack_name = "Gol_AttackDown.png";
-        let gol_attack_down = Asset::new(Image::load(gol_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    /// Flash the screen red, such as when the player is hurt
+    fn flash(&mut self) {
+        self.schedule(0.0, Effect::Flash);
+    }
 
-            return Ok(gol_attack);
-        }));
+    fn start(&mut self, effect: Effect, palette: &Palette) {
+        match effect {
+            Effect::Shake => self.shake_time = SCREEN_SHAKE_TIME,
 
-        let gol_attack_name = "Gol_AttackRight.png";
-        let gol_attack_right = Asset::new(Image::load(gol_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+            Effect::Flash => self.flash_time = SCREEN_FLASH_TIME,
 
-            return Ok(gol_attack);
-        }));
+            Effect::Dust(pos) => {
+                let dust = Particle::burst(pos, DUST_PARTICLES, (0.4, 1.0), &[palette.dust, palette.wall], false);
+                self.particles.extend(dust);
+            },
 
-        let gol_attack_name = "Gol_AttackLeft.png";
-        let gol_attack_left = Asset::new(Image::load(gol_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+            Effect::Sparkle(pos) => {
+                let sparkles = Particle::burst(pos, SPARKLE_PARTICLES, (0.2, 0.8), &[palette.sparkle, palette.text], true);
+                self.particles.extend(sparkles);
+            },
+        }
+    }
 
-            return Ok(gol_attack);
-        }));
+    fn update(&mut self, dt: f64, palette: &Palette) {
+        self.shake_time = (self.shake_time - dt).max(0.0);
+        self.flash_time = (self.flash_time - dt).max(0.0);
 
-        let rook_attack_name = "Rook_AttackUp.png";
-        let rook_attack_up = Asset::new(Image::load(rook_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
+        let mut started = Vec::new();
+        for (delay, effect) in self.scheduled.iter_mut() {
+            *delay -= dt;
+            if *delay <= 0.0 {
+                started.push(*effect);
             }
+        }
+        self.scheduled.retain(|(delay, _effect)| *delay > 0.0);
+        for effect in started {
+            self.start(effect, palette);
+        }
 
-            return Ok(rook_attack);
-        }));
+        for particle in self.particles.iter_mut() {
+            particle.pos += particle.velocity * dt as f32;
+            particle.age += dt;
+        }
+        self.particles.retain(|particle| particle.age < particle.life);
+    }
 
-        let rook_attack_name = "Rook_AttackDown.png";
-        let rook_attack_down = Asset::new(Image::load(rook_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    /// How far to move the map this frame, in pixels
+    fn shake_offset(&self) -> Vector {
+        if self.shake_time <= 0.0 {
+            return Vector::new(0, 0);
+        }
 
-            return Ok(rook_attack);
-        }));
+        let strength = SCREEN_SHAKE_PX * (self.shake_time / SCREEN_SHAKE_TIME) as f32;
+        let time = self.shake_time as f32;
+        return Vector::new((time * 90.0).sin() * strength, (time * 70.0).cos() * strength);
+    }
 
-        let rook_attack_name = "Rook_AttackRight.png";
-        let rook_attack_right = Asset::new(Image::load(rook_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    fn draw<R: Renderer>(&self, renderer: &mut R, offset_px: Vector, screen: Rectangle, palette: &Palette) {
+        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+        let glyph_center_px = Vector::new(8, 8);
+        for particle in self.particles.iter() {
+            let pos = offset_px + particle.pos.times(tile_size_px) + glyph_center_px;
+            renderer.draw_rect(Rectangle::new(pos, (PARTICLE_SIZE_PX, PARTICLE_SIZE_PX)),
+                               particle.color.with_alpha(particle.alpha()));
+        }
 
-            return Ok(rook_attack);
-        }));
+        if self.flash_time > 0.0 {
+            let alpha = SCREEN_FLASH_ALPHA * (self.flash_time / SCREEN_FLASH_TIME) as f32;
+            renderer.draw_rect(screen, palette.hurt.with_alpha(alpha));
+        }
+    }
+}
 
-        let rook_attack_name = "Rook_AttackLeft.png";
-        let rook_attack_left = Asset::new(Image::load(rook_attack_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_attack = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_attack.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+impl EventListener for Effects {
+    fn on_event(&mut self, event: &GameEvent) {
+        match event {
+            GameEvent::TrapTriggered(trigger) => self.trap_triggered(trigger),
+            GameEvent::Damaged { typ: EntityType::Player(_), .. } => self.flash(),
+            _ => (),
+        }
+    }
+}
 
-            return Ok(rook_attack);
-        }));
+/// What happened each turn, in words, newest last
+struct MessageLog {
+    messages: Vec<String>,
+    // how many lines back from the newest the panel is scrolled
+    scroll: usize,
+}
 
-        let player_idle_name = "Player_Idle.png";
-        let player_idle = Asset::new(Image::load(player_idle_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut player_idle = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                player_idle.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+impl MessageLog {
+    fn new() -> MessageLog {
+        return MessageLog {
+            messages: Vec::new(),
+            scroll: 0,
+        };
+    }
 
-            return Ok(player_idle);
-        }));
+    fn add(&mut self, message: String) {
+        // keep showing the same lines while scrolled back
+        if self.scroll > 0 {
+            self.scroll += wrap_text(&message, LOG_LINE_CHARS).len();
+        }
 
-        let gol_death_name = "Gol_Die.png";
-        let gol_death = Asset::new(Image::load(gol_death_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut gol_death = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                gol_death.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+        self.messages.push(message);
+        if self.messages.len() > MAX_LOG_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.scroll = self.scroll.min(self.max_scroll());
+    }
 
-            return Ok(gol_death);
-        }));
+    fn clear(&mut self) {
+        self.messages.clear();
+        self.scroll = 0;
+    }
 
-        let trap_win_name = "McMuffin.png";
-        let trap_win = Asset::new(Image::load(trap_win_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_win = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_win.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    /// Every message, wrapped to fit the panel
+    fn lines(&self) -> Vec<String> {
+        return self.messages.iter().flat_map(|message| wrap_text(message, LOG_LINE_CHARS)).collect();
+    }
 
-            return Ok(trap_win);
-        }));
+    fn max_scroll(&self) -> usize {
+        return self.lines().len().saturating_sub(LOG_VISIBLE_LINES);
+    }
 
-        let trap_damage_name = "DamageTrap.png";
-        let trap_damage = Asset::new(Image::load(trap_damage_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_damage = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_damage.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+    /// Scroll back towards older messages, or forward with a negative amount
+    fn scroll_by(&mut self, amount: i32) {
+        let scroll = (self.scroll as i32 + amount).max(0) as usize;
+        self.scroll = scroll.min(self.max_scroll());
+    }
 
-            return Ok(trap_damage);
-        }));
+    /// The lines that fit in the panel at the current scroll position
+    fn visible_lines(&self) -> Vec<String> {
+        let lines = self.lines();
+        let end = lines.len() - self.scroll.min(lines.len());
+        let start = end.saturating_sub(LOG_VISIBLE_LINES);
+        return lines[start..end].to_vec();
+    }
+}
 
-        let trap_arrow_up_name = "DirectionPushTrapUp.png";
-        let trap_arrow_up = Asset::new(Image::load(trap_arrow_up_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_arrow_up = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_arrow_up.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+impl EventListener for MessageLog {
+    fn on_event(&mut self, event: &GameEvent) {
+        let message =
+            match *event {
+                GameEvent::Damaged { typ, amount, cause: Cause::Attack(attacker), .. } => {
+                    match (attacker, typ) {
+                        (_, EntityType::Player(_)) => format!("The {} attacks you (-{})", entity_name(&attacker), amount),
+                        _ => format!("The {} attacks the {} (-{})", entity_name(&attacker), entity_name(&typ), amount),
+                    }
+                },
 
-            return Ok(trap_arrow_up);
-        }));
+                GameEvent::Damaged { typ, amount, cause: Cause::Trap(_), .. } => {
+                    format!("{} (-{})", sentence(&typ, "are hurt", "is hurt"), amount)
+                },
 
-        let trap_arrow_down_name = "DirectionPushTrapDown.png";
-        let trap_arrow_down = Asset::new(Image::load(trap_arrow_down_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_arrow_down = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_arrow_down.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+                GameEvent::TrapTriggered(trigger) => {
+                    match trigger.trap {
+                        Trap::Berserk => sentence(&trigger.typ, "go berserk", "goes berserk"),
+                        Trap::Kill => sentence(&trigger.typ, "step on a kill trap", "steps on a kill trap"),
+                        Trap::Bump => sentence(&trigger.typ, "are thrown by a bump trap", "is thrown by a bump trap"),
+                        Trap::Teleport => sentence(&trigger.typ, "teleport", "teleports"),
+                        Trap::CountDown(0) => sentence(&trigger.typ, "set off a count down trap", "sets off a count down trap"),
+                        Trap::CountDown(n) => format!("A count down trap ticks down to {}", n - 1),
+                        Trap::Arrow(dir) => {
+                            let direction_name = format!("{:?}", dir).to_lowercase();
+                            sentence(&trigger.typ,
+                                     &format!("slide {}", direction_name),
+                                     &format!("slides {}", direction_name))
+                        },
 
-            return Ok(trap_arrow_down);
-        }));
+                        // leaving the level is logged when the level is cleared
+                        Trap::NextLevel | Trap::Win => return,
+                    }
+                },
 
-        let trap_arrow_right_name = "DirectionPushTrapRight.png";
-        let trap_arrow_right = Asset::new(Image::load(trap_arrow_right_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_arrow_right = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_arrow_right.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+                GameEvent::Died { typ: EntityType::Player(_), .. } => "You die".to_string(),
 
-            return Ok(trap_arrow_right);
-        }));
+                GameEvent::Died { typ, .. } => sentence(&typ, "die", "dies"),
 
-        let trap_arrow_left_name = "DirectionPushTrapLeft.png";
-        let trap_arrow_left = Asset::new(Image::load(trap_arrow_left_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_arrow_left = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_arrow_left.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+                GameEvent::LevelCleared(level) => format!("You clear level {}", level + 1),
 
-            return Ok(trap_arrow_left);
-        }));
+                GameEvent::Moved { .. } | GameEvent::Attacked { .. } => return,
+            };
 
-        let trap_random_direction_name = "RandomDirectionTrap.png";
-        let trap_random_direction = Asset::new(Image::load(trap_random_direction_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_random_direction = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_random_direction.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+        self.add(message);
+    }
+}
 
-            return Ok(trap_random_direction);
-        }));
+fn entity_name(typ: &EntityType) -> &'static str {
+    return match typ {
+        EntityType::Player(_) => "player",
+        EntityType::Monster(monster) => monster.typ.name(),
+        EntityType::Trap(trap) => trap.name(),
+    };
+}
 
-        let trap_tele_name = "TeleTrap.png";
-        let trap_tele = Asset::new(Image::load(trap_tele_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut trap_tele = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                trap_tele.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+/// "You ..." for the player, and "The Gol ..." for anything else
+fn sentence(typ: &EntityType, player_verb: &str, other_verb: &str) -> String {
+    return match typ {
+        EntityType::Player(_) => format!("You {}", player_verb),
+        EntityType::Monster(monster) => format!("The {} {}", monster.typ.name(), other_verb),
+        EntityType::Trap(trap) => format!("The {} trap {}", trap.name(), other_verb),
+    };
+}
 
-            return Ok(trap_tele);
-        }));
+fn generate_map<R: Rng>(size: Vector, rng: &mut R) -> Vec<Tile> {
+    let width = size.x as usize;
+    let height = size.y as usize;
+    let mut map = Vec::with_capacity(width * height);
+    for x in 0..width {
+        for y in 0..height {
+            let mut tile = Tile::wall(x, y);
 
-        let rook_death_name = "Rook_Die.png";
-        let rook_death = Asset::new(Image::load(rook_death_name).and_then(|image| {
-            let num_sprites: u32 = image.area().size().x as u32 / 16;
-            let mut rook_death = Vec::new();
-            let anim_size = Vector::new(16, 16);
-            for image_index in 0..num_sprites {
-                let pos = Vector::new(image_index * 16, 0);
-                rook_death.push(image.subimage(Rectangle::new(pos, anim_size)));
-            }
+            if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
+                tile.glyph = WALL_CHAR;
+                tile.blocks = true;
+            };
+            map.push(tile);
+        }
+    }
+
+    let mut walls_placed = 0;
+    while walls_placed < 5 {
+        let mut x = rng.gen_range(2, MAP_WIDTH as i32);
+        let mut y = rng.gen_range(2, MAP_HEIGHT as i32);
+        let x_dir: i32 = rng.gen_range(-1, 2);
+        let y_dir: i32 = rng.gen_range(-1, 2);
+        let dist = rng.gen_range(1, 5);
+
+        if x_dir.abs() == y_dir.abs() {
+            continue;
+        }
+
+        for _square_index in 0..dist {
+            if let Some(map_index) = map.iter().position(|tile| tile.pos.x == x as f32 && tile.pos.y == y as f32) {
+                map[map_index] = Tile::wall(x as usize, y as usize);
+                map[map_index].glyph = WALL_CHAR;
+                map[map_index].blocks = true;
+                x += x_dir;
+                y += y_dir;
+                if x < 0 || x >= MAP_WIDTH as i32 || y < 0 || y > MAP_HEIGHT as i32 {
+                    break;
+                }
+            }
+        }
+
+        if rng.gen_range(0.0, 1.0) > 0.5 {
+            let x_dir = -x_dir;
+            let y_dir = -y_dir;
+            x += x_dir;
+            y += y_dir;
+            if let Some(map_index) = map.iter().position(|tile| tile.pos.x == x as f32 && tile.pos.y == y as f32) {
+                map[map_index] = Tile::wall(x as usize, y as usize);
+                map[map_index].glyph = WALL_CHAR;
+                map[map_index].blocks = true;
+            }
+        }
+
+        walls_placed += 1;
+    }
+
+    return map;
+}
+
+fn blocked_tile(pos: Vector, map: &Map) -> bool {
+    return map.iter().any(|tile| tile.blocks && tile.pos == pos);
+}
+
+fn occupied_tile(pos: Vector, entities: &[Entity]) -> Option<Entity> {
+    return entities.iter().find(|entity| entity.pos == pos).cloned();
+}
+
+fn trap_tile(pos: Vector, entities: &[Entity]) -> Option<Entity> {
+    return entities.iter().find(|entity| entity.typ.is_trap() && entity.pos == pos).cloned();
+}
+
+/// Whether a monster is next to the given position, including diagonals
+fn monster_adjacent(pos: Vector, entities: &[Entity]) -> bool {
+    return entities.iter().any(|entity| {
+        entity.typ.is_monster() &&
+        (entity.pos.x - pos.x).abs() <= 1.0 &&
+        (entity.pos.y - pos.y).abs() <= 1.0
+    });
+}
+
+/// First step of the shortest walk from start to target. The walk goes around
+/// walls, monsters and known traps, although the target itself may be a trap.
+fn walk_step(start: Vector, target: Vector, map: &Map, entities: &[Entity]) -> Option<Direction> {
+    let walkable = |pos: Vector| {
+        map.iter().any(|tile| tile.pos == pos && !tile.blocks) &&
+        !entities.iter().any(|entity| {
+            entity.pos == pos &&
+            (entity.typ.is_monster() || (entity.typ.is_trap() && !entity.hidden && pos != target))
+        })
+    };
+
+    if start == target || !walkable(target) {
+        return None;
+    }
+
+    // breadth first search, carrying along the first step taken to reach each tile
+    let mut visited: HashSet<(i32, i32)> = HashSet::new();
+    let mut queue: VecDeque<(Vector, Option<Direction>)> = VecDeque::new();
+    visited.insert((start.x as i32, start.y as i32));
+    queue.push_back((start, None));
+
+    while let Some((pos, first_step)) = queue.pop_front() {
+        for dir in DIRECTIONS.iter() {
+            let next = pos + dir.offset();
+            if !visited.insert((next.x as i32, next.y as i32)) || !walkable(next) {
+                continue;
+            }
+
+            let step = first_step.unwrap_or(*dir);
+            if next == target {
+                return Some(step);
+            }
+
+            queue.push_back((next, Some(step)));
+        }
+    }
+
+    return None;
+}
+
+/// The map tile under a point on the screen, if there is one.
+/// Glyphs are 16 pixels drawn at their tile position and scaled by SCALE
+/// around their center, so each tile is centered 8 pixels in from where it is drawn.
+fn screen_to_map(screen_pos: Vector) -> Option<Vector> {
+    let offset_px = Vector::new(MAP_DRAW_X_OFFSET as u32, MAP_DRAW_Y_OFFSET as u32);
+    let glyph_center_px = Vector::new(8, 8);
+    let glyph_half_size_px = 8.0 * SCALE;
+
+    let relative_px = screen_pos - offset_px - glyph_center_px;
+
+    // outside of the drawn map, including the overhang of the scaled border glyphs
+    let map_end_px = Vector::new((MAP_WIDTH - 1) as u32 * TILE_WIDTH_PX,
+                                 (MAP_HEIGHT - 1) as u32 * TILE_HEIGHT_PX);
+    if relative_px.x < -glyph_half_size_px || relative_px.y < -glyph_half_size_px ||
+       relative_px.x >= map_end_px.x + glyph_half_size_px || relative_px.y >= map_end_px.y + glyph_half_size_px {
+        return None;
+    }
+
+    // the tile whose center is closest to the cursor
+    let x = (relative_px.x / TILE_WIDTH_PX as f32).round();
+    let y = (relative_px.y / TILE_HEIGHT_PX as f32).round();
+
+    return Some(Vector::new(clamp(0.0, (MAP_WIDTH - 1) as f32, x),
+                            clamp(0.0, (MAP_HEIGHT - 1) as f32, y)));
+}
+
+/// Describe whatever the player would see on a tile
+fn describe_tile(pos: Vector, map: &Map, entities: &[Entity]) -> String {
+    let mut lines = Vec::new();
+
+    for entity in entities.iter().filter(|entity| entity.pos == pos) {
+        match entity.typ {
+            EntityType::Player(_) => {
+                lines.push(format!("You: HP {}/{}", entity.hp(), entity.max_hp()));
+            },
+
+            EntityType::Monster(monster) => {
+                let status = if monster.status == Some(Status::Berserk) { ", berserk" } else { "" };
+                lines.push(format!("{}: HP {}/{}{}", monster.typ.name(), entity.hp(), entity.max_hp(), status));
+            },
+
+            EntityType::Trap(trap) => {
+                if !entity.hidden {
+                    lines.push(trap.description());
+                }
+            },
+        }
+    }
+
+    if lines.is_empty() {
+        let is_wall = map.iter().any(|tile| tile.pos == pos && tile.blocks);
+        lines.push(if is_wall { "Wall".to_string() } else { "Floor".to_string() });
+    }
+
+    return lines.join(" / ");
+}
+
+/// Each component as -1, 0 or 1. Unlike f32::signum, zero stays zero.
+fn sign(vec: Vector) -> Vector {
+    let sign_of = |value: f32| {
+        if value > 0.0 {
+            1.0
+        } else if value < 0.0 {
+            -1.0
+        } else {
+            0.0
+        }
+    };
+
+    return Vector::new(sign_of(vec.x), sign_of(vec.y));
+}
+
+fn magnitude(vec: Vector) -> f32 {
+    return (vec.x.powi(2) + vec.y.powi(2)).sqrt();
+}
+
+fn clamp(min: f32, max: f32, value: f32) -> f32 {
+    let result: f32;
+
+    if value < min {
+        result = min;
+    } else if value > max {
+        result = max;
+    } else {
+        result = value;
+    }
+
+    return result;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AnimState {
+    None,
+    Idle(f64),
+}
+
+impl AnimState {
+    fn is_none(&self) -> bool {
+        match self {
+            AnimState::None => true,
+            AnimState::Idle(_) => false,
+        }
+    }
+}
+
+/// What kind of thing an animation is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SpriteKind {
+    Player,
+    Gol,
+    Rook,
+    KillTrap,
+    BumpTrap,
+    TeleportTrap,
+    WinTrap,
+    ArrowTrapUp,
+    ArrowTrapDown,
+    ArrowTrapLeft,
+    ArrowTrapRight,
+}
+
+impl SpriteKind {
+    fn monster(typ: MonsterType) -> SpriteKind {
+        return match typ {
+            MonsterType::Gol => SpriteKind::Gol,
+            MonsterType::Rook => SpriteKind::Rook,
+        };
+    }
+
+    /// Traps without their own sprites are drawn as glyphs
+    fn trap(trap: Trap) -> Option<SpriteKind> {
+        return match trap {
+            Trap::Kill => Some(SpriteKind::KillTrap),
+            Trap::Bump => Some(SpriteKind::BumpTrap),
+            Trap::Teleport => Some(SpriteKind::TeleportTrap),
+            Trap::Win => Some(SpriteKind::WinTrap),
+            Trap::Arrow(Arrow::Up) => Some(SpriteKind::ArrowTrapUp),
+            Trap::Arrow(Arrow::Down) => Some(SpriteKind::ArrowTrapDown),
+            Trap::Arrow(Arrow::Left) => Some(SpriteKind::ArrowTrapLeft),
+            Trap::Arrow(Arrow::Right) => Some(SpriteKind::ArrowTrapRight),
+            Trap::Berserk | Trap::CountDown(_) | Trap::NextLevel => None,
+        };
+    }
+
+    fn entity(typ: &EntityType) -> Option<SpriteKind> {
+        return match typ {
+            EntityType::Player(_) => Some(SpriteKind::Player),
+            EntityType::Monster(monster) => Some(SpriteKind::monster(monster.typ)),
+            EntityType::Trap(trap) => SpriteKind::trap(*trap),
+        };
+    }
+}
+
+/// What an entity is doing in an animation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AnimAction {
+    Idle,
+    AttackUp,
+    AttackDown,
+    AttackLeft,
+    AttackRight,
+    Die,
+}
+
+impl AnimAction {
+    fn attack(direction: Arrow) -> AnimAction {
+        return match direction {
+            Arrow::Up => AnimAction::AttackUp,
+            Arrow::Down => AnimAction::AttackDown,
+            Arrow::Left => AnimAction::AttackLeft,
+            Arrow::Right => AnimAction::AttackRight,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PlayMode {
+    Loop,
+    Once,
+}
+
+/// One entry in the animation manifest. The frames are laid out left to right
+/// along the top of the image file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AnimationDef {
+    kind: SpriteKind,
+    action: AnimAction,
+    file: String,
+    frame_width: u32,
+    frame_height: u32,
+    frame_count: u32,
+    frame_duration_ms: f64,
+    mode: PlayMode,
+}
+
+impl AnimationDef {
+    /// Animations need at least one frame and time to show it, otherwise
+    /// picking a frame would divide by zero or index an empty list.
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.frame_count == 0 {
+            return Err(format!("{} has a frame_count of 0", self.file));
+        }
+
+        if self.frame_duration_ms <= 0.0 || self.frame_duration_ms.is_nan() {
+            return Err(format!("{} has a frame_duration_ms of {}, it must be above 0",
+                               self.file, self.frame_duration_ms));
+        }
+
+        return Ok(());
+    }
+}
+
+struct SpriteAnimation {
+    frames: Vec<Image>,
+    frame_duration_ms: f64,
+    mode: PlayMode,
+}
+
+impl SpriteAnimation {
+    fn duration_secs(&self) -> f64 {
+        return self.frames.len() as f64 * self.frame_duration_ms / 1000.0;
+    }
+
+    /// The index of the frame to show after the given number of seconds
+    fn frame_index(&self, elapsed: f64) -> usize {
+        let frame_index = (elapsed * 1000.0 / self.frame_duration_ms) as usize;
+        return frame_index.min(self.frames.len() - 1);
+    }
+
+    /// The time into the animation after another dt seconds, or None once an
+    /// animation that plays once has finished. Looping animations start over.
+    fn advance(&self, elapsed: f64, dt: f64) -> Option<f64> {
+        let next = elapsed + dt;
+        if next < self.duration_secs() {
+            return Some(next);
+        }
+
+        return match self.mode {
+            PlayMode::Loop => Some(next % self.duration_secs()),
+            PlayMode::Once => None,
+        };
+    }
+}
+
+/// One frame out of the sprite animations
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SpriteFrame {
+    kind: SpriteKind,
+    action: AnimAction,
+    index: usize,
+}
+
+/// All sprite animations, keyed by the kind of entity and what it is doing
+struct AnimationLibrary {
+    animations: HashMap<(SpriteKind, AnimAction), SpriteAnimation>,
+}
+
+impl AnimationLibrary {
+    /// Read the manifest and load each animation's image, splitting it into frames
+    fn load(manifest: &'static str) -> impl Future<Item = AnimationLibrary, Error = quicksilver::Error> {
+        return load_file(manifest).and_then(|bytes| {
+            let defs: Vec<AnimationDef> =
+                serde_json::from_slice(&bytes).map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                        format!("Could not read {}: {}", ANIMATION_MANIFEST, err))
+                })?;
+
+            for def in defs.iter() {
+                def.validate().map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                        format!("Bad animation in {}: {}", ANIMATION_MANIFEST, err))
+                })?;
+            }
+
+            return Ok(defs);
+        }).and_then(|defs| {
+            let images = defs.iter().map(|def| Image::load(def.file.clone())).collect::<Vec<_>>();
+            return join_all(images).map(move |images| {
+                let mut animations = HashMap::new();
+                for (def, image) in defs.iter().zip(images.iter()) {
+                    let frame_size = Vector::new(def.frame_width, def.frame_height);
+                    let frames = (0..def.frame_count).map(|frame_index| {
+                        let pos = Vector::new(frame_index * def.frame_width, 0);
+                        image.subimage(Rectangle::new(pos, frame_size))
+                    }).collect();
+
+                    animations.insert((def.kind, def.action), SpriteAnimation {
+                        frames: frames,
+                        frame_duration_ms: def.frame_duration_ms,
+                        mode: def.mode,
+                    });
+                }
+
+                return AnimationLibrary { animations: animations };
+            });
+        });
+    }
+
+    fn get(&self, kind: SpriteKind, action: AnimAction) -> Option<&SpriteAnimation> {
+        return self.animations.get(&(kind, action));
+    }
+}
+
+/// A piece of visible motion. Each entity plays its tweens one after another,
+/// while the rules have already moved it to its final position.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tween {
+    Step(Vector, Vector),
+    Slide(Vector, Vector),
+    Teleport(Vector, Vector),
+    Shake(Vector),
+}
+
+impl Tween {
+    fn duration(&self) -> f64 {
+        return match self {
+            Tween::Step(_, _) => ITERP_TIME,
+            Tween::Slide(_, _) => SLIDE_STEP_TIME,
+            Tween::Teleport(_, _) => TELEPORT_TIME,
+            Tween::Shake(_) => SHAKE_TIME,
+        };
+    }
+
+    fn end(&self) -> Vector {
+        return match self {
+            Tween::Step(_, to) | Tween::Slide(_, to) | Tween::Teleport(_, to) => *to,
+            Tween::Shake(pos) => *pos,
+        };
+    }
+
+    /// Where to draw an entity, in tiles, and how opaque, a fraction
+    /// of the way through the tween
+    fn display(&self, fraction: f32) -> (Vector, f32) {
+        return match self {
+            Tween::Step(from, to) | Tween::Slide(from, to) => {
+                (Vector::new(lerp(from.x, to.x, fraction), lerp(from.y, to.y, fraction)), 1.0)
+            },
+
+            // fade out, then fade in at the destination
+            Tween::Teleport(from, to) => {
+                if fraction < 0.5 {
+                    (*from, 1.0 - fraction * 2.0)
+                } else {
+                    (*to, fraction * 2.0 - 1.0)
+                }
+            },
+
+            Tween::Shake(pos) => {
+                let offset = (fraction * std::f32::consts::PI * 6.0).sin() * SHAKE_DISTANCE * (1.0 - fraction);
+                (*pos + Vector::new(offset, 0.0), 1.0)
+            },
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entity {
+    // where the entity ends up once its tweens have played
+    last_pos: Vector,
+    tweens: VecDeque<Tween>,
+    tween_time: f64,
+    pos: Vector,
+    glyph: char,
+    typ: EntityType,
+    anim_state: AnimState,
+    hidden: bool,
+    speed: i32,
+    energy: i32,
+}
+
+impl Entity {
+    fn push_tween(&mut self, tween: Tween) {
+        self.last_pos = tween.end();
+        self.tweens.push_back(tween);
+    }
+
+    /// Animate a plain move from wherever the entity was last shown to its position
+    fn tween_to_pos(&mut self) {
+        if self.last_pos != self.pos {
+            let tween = Tween::Step(self.last_pos, self.pos);
+            self.push_tween(tween);
+        }
+    }
+
+    /// Show the entity at its position straight away
+    fn snap_to_pos(&mut self) {
+        self.last_pos = self.pos;
+        self.tweens.clear();
+        self.tween_time = 0.0;
+    }
+
+    /// Seconds until the entity is shown at its position
+    fn time_until_shown(&self) -> f64 {
+        let queued: f64 = self.tweens.iter().map(|tween| tween.duration()).sum();
+        let step = if self.last_pos != self.pos { ITERP_TIME } else { 0.0 };
+        return (queued - self.tween_time).max(0.0) + step;
+    }
+
+    fn advance_tweens(&mut self, dt: f64) {
+        self.tween_time += dt;
+        while let Some(tween) = self.tweens.front() {
+            if self.tween_time < tween.duration() {
+                break;
+            }
+            self.tween_time -= tween.duration();
+            self.tweens.pop_front();
+        }
+
+        if self.tweens.is_empty() {
+            self.tween_time = 0.0;
+        }
+    }
+
+    /// Where to draw the entity, in tiles, and how opaque
+    fn display(&self) -> (Vector, f32) {
+        return match self.tweens.front() {
+            Some(tween) => tween.display((self.tween_time / tween.duration()) as f32),
+            None => (self.pos, 1.0),
+        };
+    }
+
+    fn trap(pos: Vector, trap: Trap) -> Entity {
+        let chr = match trap {
+            Trap::Kill => KILL_TRAP_CHAR,
+            Trap::Berserk => '*',
+            Trap::Bump => 42 as char,
+            Trap::Teleport => '!',
+            Trap::CountDown(n) => (b'0' + n) as char,
+            Trap::Arrow(dir) => {
+                match dir {
+                    Arrow::Left => ARROW_LEFT_CHAR,
+                    Arrow::Right => ARROW_RIGHT_CHAR,
+                    Arrow::Up => ARROW_UP_CHAR,
+                    Arrow::Down => ARROW_DOWN_CHAR,
+                }
+            }
+            Trap::NextLevel => NEXT_LEVEL_CHAR,
+            Trap::Win => WIN_CHAR,
+        };
+
+        let anim_state =
+            match trap {
+                Trap::Arrow(_) | Trap::NextLevel | Trap::Berserk | Trap::CountDown(_) => AnimState::None,
+                _ => AnimState::Idle(0.0),
+            };
+
+        // the exits are always visible, other traps must be discovered
+        let hidden =
+            match trap {
+                Trap::NextLevel | Trap::Win => false,
+                _ => true,
+            };
+
+        Entity {
+            last_pos: pos,
+            tweens: VecDeque::new(),
+            tween_time: 0.0,
+            pos: pos,
+            glyph: chr,
+            typ: EntityType::trap(trap),
+            anim_state: anim_state,
+            hidden: hidden,
+            speed: 0,
+            energy: 0,
+        }
+    }
+
+    fn player(pos: Vector) -> Entity {
+        Entity {
+            last_pos: pos,
+            tweens: VecDeque::new(),
+            tween_time: 0.0,
+            pos: pos,
+            glyph: PLAYER_CHARACTER,
+            typ: EntityType::Player(Player {
+                hp: 5,
+                max_hp: 5,
+                status: None,
+            }),
+            anim_state: AnimState::Idle(0.0),
+            hidden: false,
+            speed: NORMAL_SPEED,
+            energy: 0,
+        }
+    }
+
+    fn gol(pos: Vector) -> Entity {
+        Entity {
+            last_pos: pos,
+            tweens: VecDeque::new(),
+            tween_time: 0.0,
+            pos: pos,
+            glyph: GOL_CHAR,
+            typ: EntityType::monster(1, MonsterType::Gol),
+            anim_state: AnimState::Idle(0.0),
+            hidden: false,
+            speed: NORMAL_SPEED,
+            energy: 0,
+        }
+    }
+
+    fn rook(pos: Vector) -> Entity {
+        Entity {
+            last_pos: pos,
+            tweens: VecDeque::new(),
+            tween_time: 0.0,
+            pos: pos,
+            glyph: ROOK_CHAR,
+            typ: EntityType::monster(2, MonsterType::Rook),
+            anim_state: AnimState::Idle(0.0),
+            hidden: false,
+            speed: NORMAL_SPEED,
+            energy: 0,
+        }
+    }
+
+    fn status(&self) -> Option<Status> {
+        return match self.typ {
+            EntityType::Monster(monster) => monster.status,
+            EntityType::Player(player) => player.status,
+            _ => None,
+        };
+    }
+
+    /// Speed after status effects are applied
+    fn speed(&self) -> i32 {
+        return match self.status() {
+            Some(Status::Berserk) => self.speed * 2,
+            None => self.speed,
+        };
+    }
+}
+
+fn map_pos<R: Rng>(rng: &mut R) -> Vector {
+    return Vector::new(rng.gen_range(1, MAP_WIDTH as u16 - 1),
+                       rng.gen_range(1, MAP_HEIGHT as u16 - 1));
+}
+
+fn map_unique_pos<'a, R: Rng>(map: Map, rng: &'a mut R) -> impl Iterator<Item=Vector> + 'a {
+    let mut positions: Vec<Vector> = Vec::new();
+    return std::iter::from_fn(move || {
+        let mut new_pos = map_pos(rng);
+        while positions.iter().find(|pos| **pos == new_pos).is_some() ||
+              map[new_pos.y as usize + new_pos.x as usize * MAP_HEIGHT].blocks {
+            new_pos = map_pos(rng);
+        }
+
+        positions.push(new_pos);
+
+        return Some(new_pos);
+    });
+}
+
+fn generate_entities<R: Rng>(last_level: bool, entities: &mut Vec<Entity>, map: &Map, rng: &mut R) -> Vector {
+    let player_pos = if false {
+        entities.push(Entity::gol(Vector::new(4, 4)));
+        entities.push(Entity::rook(Vector::new(2, 1)));
+        entities.push(Entity::trap(Vector::new(6, 6), Trap::Bump)); 
+        entities.push(Entity::trap(Vector::new(3, 8), Trap::Berserk)); 
+        entities.push(Entity::trap(Vector::new(4, 2), Trap::Arrow(Arrow::Left))); 
+        entities.push(Entity::trap(Vector::new(4, 3), Trap::Arrow(Arrow::Left))); 
+        entities.push(Entity::trap(Vector::new(4, 4), Trap::Arrow(Arrow::Left))); 
+        entities.push(Entity::trap(Vector::new(7, 6), Trap::Kill)); 
+        entities.push(Entity::trap(Vector::new(7, 7), Trap::Kill)); 
+        entities.push(Entity::trap(Vector::new(7, 2), Trap::Teleport)); 
+        entities.push(Entity::trap(Vector::new(4, 8), Trap::Teleport)); 
+        entities.push(Entity::trap(Vector::new(1, 2), Trap::CountDown(3))); 
+        entities.push(Entity::trap(Vector::new(8, 8), Trap::NextLevel));
+
+        Vector::new(3, 4)
+    } else {
+        let mut positions = map_unique_pos(map.clone(), rng);
+
+        entities.push(Entity::gol(positions.next().unwrap()));
+        entities.push(Entity::rook(positions.next().unwrap()));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Bump));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Kill));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Teleport));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Teleport));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Left)));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Right)));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Up)));
+        entities.push(Entity::trap(positions.next().unwrap(), Trap::Arrow(Arrow::Down)));
+
+        if last_level {
+            entities.push(Entity::trap(positions.next().unwrap(), Trap::Win));
+        } else {
+            entities.push(Entity::trap(positions.next().unwrap(), Trap::NextLevel));
+        }
+
+        positions.next().unwrap()
+    };
+
+    return player_pos;
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct KillRecord {
+    level: usize,
+    monster: String,
+    cause: String,
+}
+
+/// Statistics for the current run, used for the score and the end of run summary
+#[derive(Clone, Debug, Default, Serialize)]
+struct RunStats {
+    turns: u32,
+    level_turns: Vec<u32>,
+    levels_cleared: u32,
+    trap_kills: u32,
+    combat_kills: u32,
+    damage_taken: BTreeMap<String, Hp>,
+    traps_triggered: BTreeMap<String, u32>,
+    kills: Vec<KillRecord>,
+}
+
+impl RunStats {
+    fn new() -> RunStats {
+        let mut stats = RunStats::default();
+        stats.start_level();
+        return stats;
+    }
+
+    fn start_level(&mut self) {
+        self.level_turns.push(0);
+    }
+
+    fn take_turn(&mut self) {
+        self.turns += 1;
+        if let Some(turns) = self.level_turns.last_mut() {
+            *turns += 1;
+        }
+    }
+
+    fn damage(&mut self, source: &str, amount: Hp) {
+        *self.damage_taken.entry(source.to_string()).or_insert(0) += amount;
+    }
+
+    fn trap_triggered(&mut self, trap: Trap) {
+        *self.traps_triggered.entry(trap.name().to_string()).or_insert(0) += 1;
+    }
+
+    fn kill(&mut self, monster_type: MonsterType, cause: &str) {
+        let level = self.level_turns.len();
+        self.kills.push(KillRecord {
+            level: level,
+            monster: monster_type.name().to_string(),
+            cause: cause.to_string(),
+        });
+    }
+
+    fn score(&self, hp_remaining: Hp, undo_count: usize) -> i32 {
+        let score =
+            self.levels_cleared as i32 * SCORE_PER_LEVEL +
+            hp_remaining.max(0) * SCORE_PER_HP +
+            self.trap_kills as i32 * SCORE_PER_TRAP_KILL +
+            self.combat_kills as i32 * SCORE_PER_COMBAT_KILL +
+            self.turns as i32 * SCORE_PER_TURN +
+            undo_count as i32 * SCORE_PER_UNDO;
+
+        return score.max(0);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct HighScore {
+    name: String,
+    seed: u64,
+    score: i32,
+    date: String,
+}
+
+impl EventListener for RunStats {
+    fn on_event(&mut self, event: &GameEvent) {
+        match *event {
+            GameEvent::TrapTriggered(trigger) => self.trap_triggered(trigger.trap),
+
+            GameEvent::Damaged { typ: EntityType::Player(_), amount, cause, .. } => {
+                self.damage(&cause.damage_source(), amount);
+            },
+
+            GameEvent::Died { typ: EntityType::Monster(monster), cause, .. } => {
+                match cause {
+                    Cause::Attack(_) => self.combat_kills += 1,
+                    Cause::Trap(_) => self.trap_kills += 1,
+                }
+                self.kill(monster.typ, cause.kill_source());
+            },
+
+            GameEvent::LevelCleared(_) => self.levels_cleared += 1,
+
+            _ => (),
+        }
+    }
+}
+
+fn load_high_scores() -> Vec<HighScore> {
+    // there is no table until the first score is recorded
+    return saving::load::<Vec<HighScore>>(APP_NAME, HIGH_SCORE_PROFILE).unwrap_or_default();
+}
+
+/// A run's statistics as exported for balancing
+#[derive(Serialize)]
+struct RunReport<'a> {
+    seed: u64,
+    score: i32,
+    won: bool,
+    date: String,
+    stats: &'a RunStats,
+}
+
+/// Save an exported report, returning where it was saved
+#[cfg(not(target_arch = "wasm32"))]
+fn save_report(json: &str, seed: u64) -> std::result::Result<String, String> {
+    let file_name = format!("stone_fall_stats_{}.json", seed);
+    return std::fs::write(&file_name, json).map(|_| file_name).map_err(|err| err.to_string());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_report(json: &str, seed: u64) -> std::result::Result<String, String> {
+    // there is no file system on the web, so keep the report in browser storage
+    let profile = format!("stats_{}", seed);
+    return saving::save_raw(APP_NAME, &profile, json.as_bytes())
+        .map(|_| format!("browser storage ({})", profile))
+        .map_err(|err| format!("{:?}", err));
+}
+
+/// Save the message log as a text file, returning where it was saved
+#[cfg(not(target_arch = "wasm32"))]
+fn save_log(text: &str, seed: u64) -> std::result::Result<String, String> {
+    let file_name = format!("stone_fall_log_{}.txt", seed);
+    return std::fs::write(&file_name, text).map(|_| file_name).map_err(|err| err.to_string());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_log(text: &str, seed: u64) -> std::result::Result<String, String> {
+    let profile = format!("log_{}", seed);
+    return saving::save_raw(APP_NAME, &profile, text.as_bytes())
+        .map(|_| format!("browser storage ({})", profile))
+        .map_err(|err| format!("{:?}", err));
+}
+
+/// Break text into lines of at most max_chars, splitting on spaces
+fn wrap_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split(' ') {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max_chars {
+            lines.push(line);
+            line = String::new();
+        }
+
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(line);
+
+    return lines;
+}
+
+/// Insert a score into the table, keeping it sorted and at most NUM_HIGH_SCORES long.
+/// The table is kept for this session even if it cannot be saved.
+fn record_high_score(high_scores: &mut Vec<HighScore>, high_score: HighScore) -> std::result::Result<(), String> {
+    let index =
+        high_scores.iter()
+                   .position(|other| other.score < high_score.score)
+                   .unwrap_or(high_scores.len());
+    high_scores.insert(index, high_score);
+    high_scores.truncate(NUM_HIGH_SCORES);
+
+    return saving::save(APP_NAME, HIGH_SCORE_PROFILE, high_scores)
+                  .map_err(|err| format!("Could not save high scores: {:?}", err));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_time_secs() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn unix_time_secs() -> u64 {
+    return (stdweb::web::Date::now() / 1000.0) as u64;
+}
+
+/// Wall clock time in seconds, for timing animations and movement
+#[cfg(not(target_arch = "wasm32"))]
+fn now_secs() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs_f64()).unwrap_or(0.0);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now_secs() -> f64 {
+    return stdweb::web::Date::now() / 1000.0;
+}
+
+/// Size of the browser window, which the canvas is stretched to fill.
+/// Desktop windows are resized by the player instead.
+#[cfg(not(target_arch = "wasm32"))]
+fn browser_size() -> Option<Vector> {
+    return None;
+}
+
+#[cfg(target_arch = "wasm32")]
+fn browser_size() -> Option<Vector> {
+    let browser = stdweb::web::window();
+    return Some(Vector::new(browser.inner_width().max(1), browser.inner_height().max(1)));
+}
+
+/// Seconds since the given time, which is then moved up to now
+fn elapsed_secs(last_time: &mut f64) -> f64 {
+    let now = now_secs();
+    let elapsed = (now - *last_time).clamp(0.0, MAX_FRAME_TIME);
+    *last_time = now;
+    return elapsed;
+}
+
+/// Today's date as YYYY-MM-DD
+fn current_date() -> String {
+    return date_from_unix_secs(unix_time_secs());
+}
+
+fn date_from_unix_secs(secs: u64) -> String {
+    // convert days since the epoch to a civil date.
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!("{:04}-{:02}-{:02}", year, month, day);
+}
+
+/// The full state of a level at the end of a turn. Count down traps
+/// carry their remaining count, so restoring the entities restores them too.
+#[derive(Clone, Debug)]
+struct Snapshot {
+    map: Map,
+    entities: Vec<Entity>,
+    game_state: GameState,
+    stats: RunStats,
+    // bump traps roll on the rng, so undoing has to rewind it too
+    rng: StdRng,
+}
+
+struct Game {
+    game_state: GameState,
+    title: Asset<Image>,
+    mononoki_font_info: Asset<Image>,
+    square_font_info: Asset<Image>,
+    lost_game_message: Asset<Image>,
+    win_game_message: Asset<Image>,
+    char_map: Asset<HashMap<u32, Image>>,
+    inventory: Asset<Image>,
+    map_size: Vector,
+    map: Map,
+    entities: Vec<Entity>,
+    player_id: usize,
+    #[allow(dead_code)]
+    tileset: Asset<HashMap<char, Image>>,
+    noise: Perlin,
+    animation_library: Asset<AnimationLibrary>,
+    buffered_action: Option<Action>,
+    last_update_time: f64,
+    last_draw_time: f64,
+    update_dt: f64,
+    animations: Vec<Animation>,
+    font: Asset<Font>,
+    text_cache: TextCache,
+    puzzle_mode: bool,
+    history: Vec<Snapshot>,
+    undo_count: usize,
+    seed: u64,
+    rng: StdRng,
+    stats: RunStats,
+    high_scores: Vec<HighScore>,
+    player_name: String,
+    entering_name: bool,
+    score_recorded: bool,
+    show_high_scores: bool,
+    export_message: Option<String>,
+    menu_index: usize,
+    entering_seed: bool,
+    seed_input: String,
+    bindings: Bindings,
+    rebinding: Option<Action>,
+    rebound_this_frame: bool,
+    walk_target: Option<Vector>,
+    events: Vec<GameEvent>,
+    hurt_time: f64,
+    lose_delay: f64,
+    audio: Audio,
+    effects: Effects,
+    scale_mode: ScaleMode,
+    fullscreen: bool,
+    display_changed: bool,
+    layout: Layout,
+    browser_size: Option<Vector>,
+    palettes: Asset<Vec<Palette>>,
+    palette: Palette,
+    palette_name: String,
+    log: MessageLog,
+    show_intents: bool,
+    intent: Option<MonsterIntent>,
+    preview_dir: Option<Direction>,
+    move_preview: Option<MovePreview>,
+    gamepad_bindings: GamepadBindings,
+    input_device: InputDevice,
+    held_direction: Option<Direction>,
+    held_time: f64,
+    next_repeat_time: f64,
+}
+
+impl Game {
+    /// Start a new run from the first level
+    fn start_run(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+        self.log.clear();
+
+        self.entities.clear();
+        self.entities.push(Entity::player(Vector::new(0, 0)));
+        self.generate_level(0);
+
+        self.game_state = GameState::Playing(0);
+        self.stats = RunStats::new();
+        self.undo_count = 0;
+        self.animations.clear();
+        self.buffered_action = None;
+        self.hurt_time = 0.0;
+        self.effects.clear();
+        self.entering_name = false;
+        self.score_recorded = false;
+        self.show_high_scores = false;
+        self.export_message = None;
+        self.reset_history();
+    }
+
+    /// Generate the map and entities for a level, keeping the player
+    fn generate_level(&mut self, level: usize) {
+        self.map = generate_map(self.map_size, &mut self.rng);
+
+        let player = self.entities[self.player_id].clone();
+        self.entities.clear();
+        self.entities.push(player);
+        let player_pos = generate_entities(level + 1 == NUM_LEVEL_GAME, &mut self.entities, &self.map, &mut self.rng);
+        self.entities[self.player_id].pos = player_pos;
+        self.entities[self.player_id].snap_to_pos();
+        reveal_traps(&mut self.entities, player_pos, TRAP_DETECTION_RADIUS);
+        self.walk_target = None;
+        self.intent = None;
+        self.move_preview = None;
+        self.audio.queued.push(SoundEffect::LevelStart);
+        self.log.add(format!("You enter level {}", level + 1));
+    }
+
+    fn menu_item_enabled(&self, item: MenuItem) -> bool {
+        return match item {
+            MenuItem::Continue => match self.game_state {
+                GameState::Paused(_) => true,
+                _ => false,
+            },
+
+            _ => true,
+        };
+    }
+
+    fn menu_item_label(&self, item: MenuItem) -> String {
+        return match item {
+            MenuItem::NewRun => "New Run".to_string(),
+            MenuItem::Continue => "Continue".to_string(),
+            MenuItem::SeededRun => {
+                if self.entering_seed {
+                    format!("Seed: {}_", self.seed_input)
+                } else {
+                    "Seeded Run".to_string()
+                }
+            },
+            MenuItem::Options => "Options".to_string(),
+            MenuItem::Quit => "Quit".to_string(),
+        };
+    }
+
+    fn option_label(&self, item: OptionItem) -> String {
+        return match item {
+            OptionItem::PuzzleMode => format!("Puzzle Mode: {}", if self.puzzle_mode { "On" } else { "Off" }),
+            OptionItem::MasterVolume => format!("Master Volume: {}%", self.audio.master_volume),
+            OptionItem::MusicVolume => format!("Music Volume: {}%", self.audio.music_volume),
+            OptionItem::EffectsVolume => format!("Effects Volume: {}%", self.audio.effects_volume),
+            OptionItem::Mute => format!("Mute: {}", if self.audio.muted { "On" } else { "Off" }),
+            OptionItem::ScreenEffects => format!("Screen Effects: {}", if self.effects.enabled { "On" } else { "Off" }),
+            OptionItem::Scaling => format!("Scaling: {}", self.scale_mode.name()),
+            OptionItem::Fullscreen => format!("Fullscreen: {}", if self.fullscreen { "On" } else { "Off" }),
+            OptionItem::Palette => format!("Colors: {}", self.palette_name),
+            OptionItem::Intents => format!("Monster Intents: {}", if self.show_intents { "On" } else { "Off" }),
+            OptionItem::Controls => "Controls".to_string(),
+            OptionItem::Back => "Back".to_string(),
+        };
+    }
+
+    /// Name of the first key or button bound to an action, for on screen
+    /// prompts. This follows whichever device was used last.
+    fn key_hint(&self, action: Action) -> String {
+        if self.input_device == InputDevice::Gamepad {
+            if let Some(button) = gamepad_buttons_for(&self.gamepad_bindings, action).first() {
+                return gamepad_button_name(*button).to_string();
+            }
+        }
+
+        return keys_for(&self.bindings, action).first()
+                                               .map(|key| key_name(*key))
+                                               .unwrap_or("(unbound)".to_string());
+    }
+
+    /// The action the player chose this frame out of the candidates, from
+    /// either the keyboard or a gamepad
+    fn read_action(&mut self, window: &Window, candidates: &[Action]) -> Option<Action> {
+        if let Some(action) = poll_action(window, &self.bindings, candidates) {
+            self.input_device = InputDevice::Keyboard;
+            return Some(action);
+        }
+
+        if let Some(action) = poll_gamepad(window, &self.gamepad_bindings, candidates) {
+            self.input_device = InputDevice::Gamepad;
+            return Some(action);
+        }
+
+        // a direction fires when first held, then repeats after a delay
+        let direction = gamepad_direction(window);
+        let mut fire = false;
+        if direction != self.held_direction {
+            self.held_direction = direction;
+            self.held_time = 0.0;
+            self.next_repeat_time = STICK_REPEAT_DELAY;
+            fire = direction.is_some();
+        } else if direction.is_some() {
+            self.held_time += self.update_dt;
+            if self.held_time >= self.next_repeat_time {
+                self.next_repeat_time += STICK_REPEAT_INTERVAL;
+                fire = true;
+            }
+        }
+
+        if fire {
+            self.input_device = InputDevice::Gamepad;
+            let action = Action::Move(direction.unwrap());
+            if candidates.contains(&action) {
+                return Some(action);
+            }
+        }
+
+        return None;
+    }
+
+    /// Whether a key or button for the action is down, for actions that last while held
+    fn action_held(&self, window: &Window, action: Action) -> bool {
+        let keys = keys_for(&self.bindings, action);
+        let buttons = gamepad_buttons_for(&self.gamepad_bindings, action);
+        return keys.iter().any(|key| window.keyboard()[*key].is_down()) ||
+               window.gamepads().iter().any(|pad| buttons.iter().any(|button| pad[*button].is_down()));
+    }
+
+    /// Apply the scaling and fullscreen settings, and on the web keep the
+    /// canvas the size of the browser window. The layout is fitted to the
+    /// window here, before anything reads the mouse or draws.
+    fn update_display(&mut self, window: &mut Window) {
+        // F11 works everywhere except while choosing a key to bind
+        if self.rebinding.is_none() &&
+           poll_action(window, &self.bindings, &[Action::ToggleFullscreen]).is_some() {
+            self.fullscreen = !self.fullscreen;
+            self.display_changed = true;
+            self.save_config();
+        }
+
+        if self.display_changed {
+            window.set_fullscreen(self.fullscreen);
+            self.display_changed = false;
+        }
+
+        let browser_size = browser_size();
+        if let Some(size) = browser_size {
+            if browser_size != self.browser_size {
+                self.browser_size = browser_size;
+                window.set_size(size);
+            }
+        }
+
+        let layout = Layout::new(window.screen_size(), self.scale_mode);
+        if layout != self.layout {
+            self.layout = layout;
+            window.set_view(layout.view());
+        }
+    }
+
+    /// Pass this turn's events to everything that listens for them
+    fn dispatch_events(&mut self) {
+        let events: Vec<GameEvent> = self.events.drain(..).collect();
+        for event in events.iter() {
+            self.stats.on_event(event);
+            self.audio.on_event(event);
+            self.effects.on_event(event);
+            self.log.on_event(event);
+            self.show_event(event);
+        }
+    }
+
+    /// Animations for an event, and losing the game when the player dies
+    fn show_event(&mut self, event: &GameEvent) {
+        match *event {
+            GameEvent::Attacked { attacker: EntityType::Monster(monster), pos, direction, .. } => {
+                self.animations.push(Animation::MonsterAttack(monster.typ, pos, direction, 0.0));
+            },
+
+            GameEvent::Damaged { typ: EntityType::Player(_), .. } => {
+                self.hurt_time = HURT_FLASH_TIME;
+            },
+
+            GameEvent::Died { typ: EntityType::Monster(monster), pos, .. } => {
+                self.animations.push(Animation::MonsterDeath(monster.typ, pos, 0.0));
+            },
+
+            GameEvent::Died { typ: EntityType::Player(_), pos, .. } => {
+                self.animations.push(Animation::PlayerDeath(pos, 0.0));
+                self.game_state = GameState::Lost;
+                self.lose_delay = LOSE_SCREEN_DELAY;
+            },
+
+            _ => (),
+        }
+    }
+
+    /// Switch to the chosen palette once the palette files have loaded
+    fn update_palette(&mut self) {
+        if self.palette.name == self.palette_name {
+            return;
+        }
+
+        let name = self.palette_name.clone();
+        let mut chosen = None;
+        let mut loaded = false;
+        let _ = self.palettes.execute(|palettes| {
+            loaded = true;
+            chosen = palettes.iter().find(|palette| palette.name == name).cloned();
+            return Ok(());
+        });
+
+        match chosen {
+            Some(palette) => self.palette = palette,
+
+            // a palette that no longer exists falls back to the default
+            None if loaded => self.palette_name = self.palette.name.clone(),
+
+            None => (),
+        }
+    }
+
+    fn cycle_palette(&mut self, backwards: bool) {
+        let mut names = Vec::new();
+        let _ = self.palettes.execute(|palettes| {
+            names = palettes.iter().map(|palette| palette.name.clone()).collect();
+            return Ok(());
+        });
+
+        if names.is_empty() {
+            return;
+        }
+
+        let index = names.iter().position(|name| *name == self.palette_name).unwrap_or(0);
+        let next =
+            if backwards {
+                (index + names.len() - 1) % names.len()
+            } else {
+                (index + 1) % names.len()
+            };
+        self.palette_name = names[next].clone();
+    }
+
+    fn save_config(&self) {
+        let config = Config {
+            puzzle_mode: self.puzzle_mode,
+            bindings: bindings_to_config(&self.bindings),
+            master_volume: self.audio.master_volume,
+            music_volume: self.audio.music_volume,
+            effects_volume: self.audio.effects_volume,
+            muted: self.audio.muted,
+            effects: self.effects.enabled,
+            scale_mode: self.scale_mode,
+            fullscreen: self.fullscreen,
+            palette: self.palette_name.clone(),
+            show_intents: self.show_intents,
+        };
+
+        if let Err(err) = saving::save(APP_NAME, CONFIG_PROFILE, &config) {
+            eprintln!("Could not save settings: {:?}", err);
+        }
+    }
+
+    /// Main and pause menu
+    fn update_menu(&mut self, window: &mut Window) {
+        let action = self.read_action(window, &MENU_ACTIONS);
+
+        if self.entering_seed {
+            if action == Some(Action::Confirm) {
+                self.entering_seed = false;
+                if let Ok(seed) = self.seed_input.parse::<u64>() {
+                    self.start_run(seed);
+                }
+            } else if action == Some(Action::Cancel) {
+                self.entering_seed = false;
+            }
+            return;
+        }
+
+        match action {
+            Some(Action::Cancel) => {
+                if let GameState::Paused(n) = self.game_state {
+                    self.game_state = GameState::Playing(n);
+                }
+            },
+
+            Some(Action::Move(Direction::Up)) => {
+                self.menu_index = (self.menu_index + MENU_ITEMS.len() - 1) % MENU_ITEMS.len();
+                if !self.menu_item_enabled(MENU_ITEMS[self.menu_index]) {
+                    self.menu_index = (self.menu_index + MENU_ITEMS.len() - 1) % MENU_ITEMS.len();
+                }
+            },
+
+            Some(Action::Move(Direction::Down)) => {
+                self.menu_index = (self.menu_index + 1) % MENU_ITEMS.len();
+                if !self.menu_item_enabled(MENU_ITEMS[self.menu_index]) {
+                    self.menu_index = (self.menu_index + 1) % MENU_ITEMS.len();
+                }
+            },
+
+            Some(Action::Confirm) => {
+                match MENU_ITEMS[self.menu_index] {
+                    MenuItem::NewRun => {
+                        self.start_run(random_seed());
+                    },
+
+                    MenuItem::Continue => {
+                        if let GameState::Paused(n) = self.game_state {
+                            self.game_state = GameState::Playing(n);
+                        }
+                    },
+
+                    MenuItem::SeededRun => {
+                        self.entering_seed = true;
+                        self.seed_input.clear();
+                    },
+
+                    MenuItem::Options => {
+                        self.game_state = GameState::Options(Box::new(self.game_state.clone()));
+                        self.menu_index = 0;
+                    },
+
+                    MenuItem::Quit => {
+                        window.close();
+                    },
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    /// Options menu, returning to the previous menu when done
+    fn update_options(&mut self, window: &mut Window, previous: GameState) {
+        let action = self.read_action(window, &MENU_ACTIONS);
+
+        let mut back = false;
+        match action {
+            Some(Action::Move(Direction::Up)) => {
+                self.menu_index = (self.menu_index + OPTION_ITEMS.len() - 1) % OPTION_ITEMS.len();
+            },
+
+            Some(Action::Move(Direction::Down)) => {
+                self.menu_index = (self.menu_index + 1) % OPTION_ITEMS.len();
+            },
+
+            Some(Action::Cancel) => {
+                back = true;
+            },
+
+            Some(Action::Confirm) | Some(Action::Move(Direction::Left)) | Some(Action::Move(Direction::Right)) => {
+                match OPTION_ITEMS[self.menu_index] {
+                    OptionItem::PuzzleMode => {
+                        self.puzzle_mode = !self.puzzle_mode;
+                        self.reset_history();
+                        self.save_config();
+                    },
+
+                    OptionItem::MasterVolume | OptionItem::MusicVolume | OptionItem::EffectsVolume => {
+                        let volume =
+                            match OPTION_ITEMS[self.menu_index] {
+                                OptionItem::MasterVolume => &mut self.audio.master_volume,
+                                OptionItem::MusicVolume => &mut self.audio.music_volume,
+                                _ => &mut self.audio.effects_volume,
+                            };
+
+                        // left turns the volume down, while right and confirm turn it up,
+                        // with confirm wrapping around to silent
+                        *volume =
+                            match action {
+                                Some(Action::Move(Direction::Left)) => volume.saturating_sub(VOLUME_STEP),
+                                Some(Action::Move(Direction::Right)) => (*volume + VOLUME_STEP).min(100),
+                                _ => if *volume >= 100 { 0 } else { (*volume + VOLUME_STEP).min(100) },
+                            };
+                        self.save_config();
+                    },
+
+                    OptionItem::Mute => {
+                        self.audio.toggle_mute();
+                        self.save_config();
+                    },
+
+                    OptionItem::ScreenEffects => {
+                        self.effects.toggle();
+                        self.save_config();
+                    },
+
+                    OptionItem::Scaling => {
+                        self.scale_mode = self.scale_mode.next();
+                        self.display_changed = true;
+                        self.save_config();
+                    },
+
+                    OptionItem::Fullscreen => {
+                        self.fullscreen = !self.fullscreen;
+                        self.display_changed = true;
+                        self.save_config();
+                    },
+
+                    OptionItem::Intents => {
+                        self.show_intents = !self.show_intents;
+                        self.save_config();
+                    },
+
+                    OptionItem::Palette => {
+                        let backwards = action == Some(Action::Move(Direction::Left));
+                        self.cycle_palette(backwards);
+                        self.save_config();
+                    },
+
+                    OptionItem::Controls => {
+                        if action == Some(Action::Confirm) {
+                            self.game_state = GameState::Controls(Box::new(self.game_state.clone()));
+                            self.menu_index = 0;
+                        }
+                    },
+
+                    OptionItem::Back => {
+                        back = true;
+                    },
+                }
+            },
+
+            _ => (),
+        }
+
+        if back {
+            self.game_state = previous;
+            self.menu_index = 0;
+        }
+    }
+
+    /// Controls screen. Confirming an action waits for a key press, which
+    /// replaces that action's keys.
+    fn update_controls(&mut self, window: &mut Window, previous: GameState) {
+        // the key that was just bound is still pressed this frame
+        if self.rebinding.is_some() || self.rebound_this_frame {
+            self.rebound_this_frame = false;
+            return;
+        }
+
+        // one line per action, then reset and back
+        let num_items = self.bindings.len() + 2;
+        let reset_index = self.bindings.len();
+        let back_index = self.bindings.len() + 1;
+
+        let action = self.read_action(window, &MENU_ACTIONS);
+        match action {
+            Some(Action::Move(Direction::Up)) => {
+                self.menu_index = (self.menu_index + num_items - 1) % num_items;
+            },
+
+            Some(Action::Move(Direction::Down)) => {
+                self.menu_index = (self.menu_index + 1) % num_items;
+            },
+
+            Some(Action::Cancel) => {
+                self.game_state = previous;
+                self.menu_index = OPTION_ITEMS.iter().position(|item| *item == OptionItem::Controls).unwrap();
+            },
+
+            Some(Action::Confirm) => {
+                if self.menu_index == reset_index {
+                    self.bindings = default_bindings();
+                    self.save_config();
+                } else if self.menu_index == back_index {
+                    self.game_state = previous;
+                    self.menu_index = OPTION_ITEMS.iter().position(|item| *item == OptionItem::Controls).unwrap();
+                } else {
+                    self.rebinding = Some(self.bindings[self.menu_index].0);
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    fn in_main_menu(&self) -> bool {
+        return match &self.game_state {
+            GameState::MainMenu => true,
+            GameState::Options(previous) | GameState::Controls(previous) => {
+                match &**previous {
+                    GameState::MainMenu => true,
+                    GameState::Options(previous) => **previous == GameState::MainMenu,
+                    _ => false,
+                }
+            },
+            _ => false,
+        };
+    }
+
+    /// Draw the main, pause, options or controls menu
+    fn draw_menu(&mut self, window: &mut Window) -> Result<()> {
+        let mut lines: Vec<(String, bool)> = Vec::new();
+        match self.game_state {
+            GameState::Options(_) => {
+                for item in OPTION_ITEMS.iter() {
+                    lines.push((self.option_label(*item), true));
+                }
+            },
+
+            GameState::Controls(_) => {
+                for (action, keys) in self.bindings.iter() {
+                    let key_names: Vec<String> = keys.iter().map(|key| key_name(*key)).collect();
+                    let button_names: Vec<&str> =
+                        gamepad_buttons_for(&self.gamepad_bindings, *action).iter()
+                                                                            .map(|button| gamepad_button_name(*button))
+                                                                            .collect();
+                    let line =
+                        if self.rebinding == Some(*action) {
+                            format!("{}: press a key...", action.name())
+                        } else if button_names.is_empty() {
+                            format!("{}: {}", action.name(), key_names.join(", "))
+                        } else {
+                            format!("{}: {}  [{}]", action.name(), key_names.join(", "), button_names.join(", "))
+                        };
+                    lines.push((line, true));
+                }
+                lines.push(("Reset to defaults".to_string(), true));
+                lines.push(("Back".to_string(), true));
+            },
+
+            _ => {
+                for item in MENU_ITEMS.iter() {
+                    lines.push((self.menu_item_label(*item), self.menu_item_enabled(*item)));
+                }
+            },
+        }
+
+        // the controls list is long, so it gets a bigger panel and smaller text
+        let (panel_pos, panel_size, line_height, text_size) =
+            match self.game_state {
+                GameState::Controls(_) => (Vector::new(150, 20), Vector::new(500, 560), 18.0, 13.0),
+                _ => (Vector::new(250, 150), Vector::new(300.0, (40.0 + lines.len() as f32 * 30.0).max(220.0)), 30.0, 20.0),
+            };
+
+        let mut renderer = WindowRenderer::new(window, &mut self.char_map, &mut self.animation_library, &mut self.font, &mut self.text_cache);
+        renderer.draw_rect(Rectangle::new(panel_pos, panel_size),
+                           self.palette.background.with_alpha(0.85));
+
+        let text_pos = panel_pos + Vector::new(20, 20);
+        for (line_index, (line, enabled)) in lines.iter().enumerate() {
+            let selected = line_index == self.menu_index;
+            let text = if selected { format!("> {}", line) } else { format!("  {}", line) };
+            let color = if *enabled { self.palette.text } else { self.palette.text_disabled };
+            renderer.draw_text(&text,
+                               text_pos + Vector::new(0.0, line_index as f32 * line_height),
+                               text_size,
+                               color)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Whether anything is still moving on screen from the last turn
+    fn motion_pending(&self) -> bool {
+        return self.entities.iter().any(|entity| !entity.tweens.is_empty());
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        return Snapshot {
+            map: self.map.clone(),
+            entities: self.entities.clone(),
+            game_state: self.game_state.clone(),
+            stats: self.stats.clone(),
+            rng: self.rng.clone(),
+        };
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.map = snapshot.map;
+        self.entities = snapshot.entities;
+        self.game_state = snapshot.game_state;
+        self.stats = snapshot.stats;
+        self.rng = snapshot.rng;
+
+        // jump straight to the restored positions rather than animating there
+        for entity in self.entities.iter_mut() {
+            entity.snap_to_pos();
+        }
+        self.animations.clear();
+        self.buffered_action = None;
+        self.hurt_time = 0.0;
+        self.effects.clear();
+        self.walk_target = None;
+        self.intent = None;
+        self.move_preview = None;
+    }
+
+    /// Start recording turns from the current state, dropping any older history
+    fn reset_history(&mut self) {
+        self.history.clear();
+        if self.puzzle_mode {
+            let snapshot = self.snapshot();
+            self.history.push(snapshot);
+        }
+    }
+
+    fn undo(&mut self) {
+        // the top of the history is the current state, so rewind to the one before it
+        if self.history.len() > 1 {
+            self.history.pop();
+            let snapshot = self.history[self.history.len() - 1].clone();
+            self.restore(snapshot);
+            self.undo_count += 1;
+            self.log.add("You undo a turn".to_string());
+        }
+    }
+
+    /// Play the action pressed, the one buffered during the last turn's motion,
+    /// or the next step towards the walk target
+    fn play_input(&mut self, action: Option<Action>, level: usize) {
+        // actions wait until the last turn has finished moving on screen
+        if self.motion_pending() {
+            if action.is_some() {
+                self.buffered_action = action;
+                self.walk_target = None;
+            }
+            return;
+        }
+        let action = action.or(self.buffered_action.take());
+
+        let player_pos = self.entities[self.player_id].pos;
+        let action =
+            match (action, self.walk_target) {
+                // any key press takes over from walking
+                (Some(action), _) => {
+                    self.walk_target = None;
+                    Some(action)
+                },
+
+                (None, Some(target)) => {
+                    let step = walk_step(player_pos, target, &self.map, &self.entities);
+                    if step.is_none() {
+                        self.walk_target = None;
+                    }
+                    step.map(Action::Move)
+                },
+
+                _ => None,
+            };
+
+        self.play_action(action, level);
+    }
+
+    /// Play the player's action, if any, then the monsters' and traps' turn
+    fn play_action(&mut self, action: Option<Action>, level: usize) {
+        let mut win = false;
+        let mut next_level = false;
+        let took_turn =
+            match action {
+                Some(action) => play_turn(&mut self.entities,
+                                          &self.map,
+                                          &mut self.rng,
+                                          self.player_id,
+                                          action,
+                                          &mut self.events,
+                                          &mut next_level,
+                                          &mut win),
+                None => false,
+            };
+
+        if took_turn {
+            self.stats.take_turn();
+            self.intent = None;
+            self.move_preview = None;
+
+            // anything that moved without a special tween just steps there
+            for entity in self.entities.iter_mut() {
+                entity.tween_to_pos();
+            }
+
+            let player_pos = self.entities[self.player_id].pos;
+
+            // stop walking when there is a monster to deal with
+            if self.walk_target == Some(player_pos) || monster_adjacent(player_pos, &self.entities) {
+                self.walk_target = None;
+            }
+
+            if next_level || win {
+                self.events.push(GameEvent::LevelCleared(level));
+            }
+
+            if next_level {
+                self.game_state = GameState::NextLevel(level);
+            } else if win {
+                self.game_state = GameState::Win;
+            }
+        }
+
+        self.dispatch_events();
+
+        self.entities = self.entities.iter().filter(|entity| {
+            if entity.typ.is_monster() {
+                return entity.hp() > 0;
+            }
+
+            return true;
+        }).cloned().collect();
+
+        if took_turn && self.puzzle_mode {
+            let snapshot = self.snapshot();
+            self.history.push(snapshot);
+        }
+    }
+
+    /// Lines for the end of run summary page
+    fn summary_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        let outcome =
+            if self.game_state == GameState::Win {
+                "Won".to_string()
+            } else {
+                format!("Lost on level {}", self.stats.level_turns.len())
+            };
+        lines.push(format!("Run Summary - {}", outcome));
+        lines.push(format!("Score: {}   Seed: {}", self.score(), self.seed));
+
+        let level_turns: Vec<String> = self.stats.level_turns.iter().map(|turns| turns.to_string()).collect();
+        lines.push(format!("Turns: {} ({})", self.stats.turns, level_turns.join(" / ")));
+        lines.push("".to_string());
+
+        let damage: Vec<String> =
+            self.stats.damage_taken.iter().map(|(source, amount)| format!("{} {}", source, amount)).collect();
+        lines.extend(wrap_text(&format!("Damage taken: {}", damage.join(", ")), SUMMARY_LINE_CHARS));
+
+        let traps: Vec<String> =
+            self.stats.traps_triggered.iter().map(|(trap, count)| format!("{} {}", trap, count)).collect();
+        lines.extend(wrap_text(&format!("Traps triggered: {}", traps.join(", ")), SUMMARY_LINE_CHARS));
+
+        lines.push(format!("Monsters killed: {} (traps {}, combat {})",
+                           self.stats.kills.len(),
+                           self.stats.trap_kills,
+                           self.stats.combat_kills));
+        let kills: Vec<String> =
+            self.stats.kills.iter()
+                            .map(|kill| format!("{} by {} (L{})", kill.monster, kill.cause, kill.level))
+                            .collect();
+        lines.extend(wrap_text(&format!("  {}", kills.join(", ")), SUMMARY_LINE_CHARS));
+
+        return lines;
+    }
+
+    /// Lines for the high score page
+    fn high_score_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.push(format!("Score: {}   Seed: {}", self.score(), self.seed));
+        lines.push("".to_string());
+        lines.push("High Scores".to_string());
+
+        for (rank, high_score) in self.high_scores.iter().enumerate() {
+            lines.push(format!("{:>2}. {:<12} {:>6} {:>10} {}",
+                               rank + 1,
+                               high_score.name,
+                               high_score.score,
+                               high_score.seed,
+                               high_score.date));
+        }
+
+        return lines;
+    }
+
+    /// Run summary or high score table, shown over the map at the end of a run
+    fn draw_end_screen(&mut self, window: &mut Window) -> Result<()> {
+        let panel_pos = Vector::new(120, 100);
+        let line_height = 20.0;
+
+        let mut lines =
+            if self.show_high_scores {
+                self.high_score_lines()
+            } else {
+                self.summary_lines()
+            };
+
+        lines.push("".to_string());
+        if self.score_recorded {
+            lines.push("Score recorded".to_string());
+        } else if self.entering_name {
+            lines.push(format!("Name: {}_", self.player_name));
+        } else {
+            lines.push(format!("Press {} to record your score", self.key_hint(Action::Confirm)));
+        }
+        lines.push(format!("{}: summary/high scores   {}: export stats",
+                           self.key_hint(Action::ToggleHighScores),
+                           self.key_hint(Action::ExportStats)));
+        lines.push(format!("{}: restart   {}: main menu",
+                           self.key_hint(Action::Restart),
+                           self.key_hint(Action::Cancel)));
+        if let Some(export_message) = &self.export_message {
+            lines.push(export_message.clone());
+        }
+
+        let mut renderer = WindowRenderer::new(window, &mut self.char_map, &mut self.animation_library, &mut self.font, &mut self.text_cache);
+        renderer.draw_rect(Rectangle::new(panel_pos, (560, 390)),
+                           self.palette.background.with_alpha(0.85));
+
+        let text_pos = panel_pos + Vector::new(10, 10);
+        for (line_index, line) in lines.iter().enumerate() {
+            if !line.is_empty() {
+                renderer.draw_text(line,
+                                   text_pos + Vector::new(0.0, line_index as f32 * line_height),
+                                   14.0,
+                                   self.palette.text)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn export_stats(&mut self) {
+        let report = RunReport {
+            seed: self.seed,
+            score: self.score(),
+            won: self.game_state == GameState::Win,
+            date: current_date(),
+            stats: &self.stats,
+        };
+
+        let message =
+            match serde_json::to_string_pretty(&report) {
+                Ok(json) => {
+                    match save_report(&json, self.seed) {
+                        Ok(location) => format!("Stats saved to {}", location),
+                        Err(err) => format!("Could not save stats: {}", err),
+                    }
+                },
+
+                Err(err) => format!("Could not export stats: {}", err),
+            };
+
+        self.export_message = Some(message);
+    }
+
+    /// Save the message log, returning a message saying where it went
+    fn export_log(&self) -> String {
+        let mut text = self.log.messages.join("\n");
+        text.push('\n');
+        return match save_log(&text, self.seed) {
+            Ok(location) => format!("Log saved to {}", location),
+            Err(err) => format!("Could not save log: {}", err),
+        };
+    }
+
+    /// Scrolling and exporting the message log, returning whether the action was one of these
+    fn update_log(&mut self, action: Option<Action>) -> bool {
+        match action {
+            Some(Action::ScrollLogUp) => self.log.scroll_by(LOG_VISIBLE_LINES as i32 / 2),
+
+            Some(Action::ScrollLogDown) => self.log.scroll_by(-(LOG_VISIBLE_LINES as i32 / 2)),
+
+            Some(Action::ExportLog) => {
+                let message = self.export_log();
+                self.log.add(message.clone());
+                self.export_message = Some(message);
+            },
+
+            _ => return false,
+        }
+
+        return true;
+    }
+
+    fn score(&self) -> i32 {
+        let hp = self.entities[self.player_id].hp();
+        return self.stats.score(hp, self.undo_count);
+    }
+
+    /// Actions for the win and lose screens. Confirm starts typing a name
+    /// and Return records the score. Other Confirm keys may be typed into the
+    /// name, so they don't submit it.
+    fn update_end_screen(&mut self, window: &mut Window) {
+        let action = self.read_action(window, &END_SCREEN_ACTIONS);
+
+        if !self.entering_name && self.update_log(action) {
+            return;
+        }
+
+        if self.entering_name {
+            let submitted =
+                window.keyboard()[Key::Return] == ButtonState::Pressed ||
+                (action == Some(Action::Confirm) && self.input_device == InputDevice::Gamepad);
+
+            if submitted {
+                let name =
+                    if self.player_name.is_empty() {
+                        "Anonymous".to_string()
+                    } else {
+                        self.player_name.clone()
+                    };
+
+                let high_score = HighScore {
+                    name: name,
+                    seed: self.seed,
+                    score: self.score(),
+                    date: current_date(),
+                };
+                if let Err(err) = record_high_score(&mut self.high_scores, high_score) {
+                    self.export_message = Some(err);
+                }
+
+                self.entering_name = false;
+                self.score_recorded = true;
+            }
+            return;
+        }
+
+        match action {
+            Some(Action::Restart) => {
+                self.start_run(random_seed());
+            },
+
+            Some(Action::Cancel) => {
+                self.game_state = GameState::MainMenu;
+                self.menu_index = 0;
+            },
+
+            Some(Action::ToggleHighScores) => {
+                self.show_high_scores = !self.show_high_scores;
+            },
+
+            Some(Action::ExportStats) => {
+                self.export_stats();
+            },
+
+            Some(Action::Undo) if self.puzzle_mode && !self.score_recorded && self.game_state == GameState::GameOver => {
+                self.undo();
+            },
+
+            Some(Action::Confirm) if !self.score_recorded => {
+                self.entering_name = true;
+            },
+
+            _ => (),
+        }
+    }
+}
+
+impl State for Game {
+    /// Load the assets and initialise the game
+    fn new() -> Result<Self> {
+        // The Mononoki font: https://madmalik.github.io/mononoki/
+        // License: SIL Open Font License 1.1
+        let font_mononoki = "mononoki-Regular.ttf";
+
+        let title = Asset::new(Font::load(font_mononoki).and_then(|font| {
+            font.render("Stone Fall", &FontStyle::new(72.0, WHITE))
+        }));
+
+        let font_image = "LD45_SpriteSheet.png";
+        let char_map = Asset::new(Image::load(font_image).and_then(|image| {
+            let mut char_map = HashMap::new();
+            let char_size = Vector::new(16, 16);
+            for char_ix in 0..256 {
+                let char_x = char_ix % 16;
+                let char_y = char_ix / 16;
+                let char_pos = Vector::new(char_x * 16, char_y * 16);
+                char_map.insert(char_ix,
+                                image.subimage(Rectangle::new(char_pos, char_size)));
+            }
+
+            return Ok(char_map);
+        }));
+
+        let animation_library = Asset::new(AnimationLibrary::load(ANIMATION_MANIFEST));
+
+        let font = Asset::new(Font::load(font_mononoki));
+
+        let lost_game_message = Asset::new(Font::load(font_mononoki).and_then(|font| {
+            font.render("You Lose!", &FontStyle::new(72.0, WHITE))
+        }));
+
+        let win_game_message = Asset::new(Font::load(font_mononoki).and_then(|font| {
+            font.render("You Win!", &FontStyle::new(72.0, WHITE))
+        }));
+
+        let mononoki_font_info = Asset::new(Font::load(font_mononoki).and_then(|font| {
+            font.render(
+                "",
+                &FontStyle::new(20.0, WHITE),
+            )
+        }));
+
+        let square_font_info = Asset::new(Font::load(font_mononoki).and_then(move |font| {
+            font.render(
+                "A Ludum Dare Game by Joel and Noah Ryan",
+                &FontStyle::new(20.0, WHITE),
+            )
+        }));
+
+        // TODO inventory message is here.
+        let inventory = Asset::new(Font::load(font_mononoki).and_then(move |font| {
+            font.render(
+                "",
+                &FontStyle::new(20.0, WHITE),
+            )
+        }));
+
+        let config = load_config();
+
+        let seed = random_seed();
+        let rng = StdRng::seed_from_u64(seed);
+
+        let map_size = Vector::new(MAP_WIDTH as u8, MAP_HEIGHT as u8);
+        let player_id = 0;
+
+        // The Square font: http://strlen.com/square/?s[]=font
+        // License: CC BY 3.0 https://creativecommons.org/licenses/by/3.0/deed.en_US
+        let font_square = "square.ttf";
+        let game_glyphs = "#@g.%";
+        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+        let tileset = Asset::new(Font::load(font_square).and_then(move |text| {
+            let tiles = text
+                .render(game_glyphs, &FontStyle::new(tile_size_px.y, WHITE))
+                .expect("Could not render the font tileset.");
+            let mut tileset = HashMap::new();
+            for (index, glyph) in game_glyphs.chars().enumerate() {
+                let pos = (index as i32 * tile_size_px.x as i32, 0);
+                let tile = tiles.subimage(Rectangle::new(pos, tile_size_px));
+                tileset.insert(glyph, tile);
+            }
+            Ok(tileset)
+        }));
+
+        let mut game = Self {
+            game_state: GameState::MainMenu,
+            title,
+            mononoki_font_info,
+            square_font_info,
+            lost_game_message,
+            win_game_message,
+            char_map,
+            inventory,
+            map_size,
+            map: Vec::new(),
+            entities: Vec::new(),
+            player_id,
+            tileset,
+            noise: Perlin::new(),
+            animation_library,
+            buffered_action: None,
+            last_update_time: now_secs(),
+            last_draw_time: now_secs(),
+            update_dt: 0.0,
+            animations: Vec::new(),
+            font,
+            text_cache: TextCache::new(),
+            puzzle_mode: config.puzzle_mode,
+            history: Vec::new(),
+            undo_count: 0,
+            seed,
+            rng,
+            stats: RunStats::new(),
+            high_scores: load_high_scores(),
+            player_name: String::new(),
+            entering_name: false,
+            score_recorded: false,
+            show_high_scores: false,
+            export_message: None,
+            menu_index: 0,
+            entering_seed: false,
+            seed_input: String::new(),
+            bindings: bindings_from_config(&config),
+            rebinding: None,
+            rebound_this_frame: false,
+            walk_target: None,
+            events: Vec::new(),
+            hurt_time: 0.0,
+            lose_delay: 0.0,
+            audio: Audio::new(&config),
+            effects: Effects::new(&config),
+            scale_mode: config.scale_mode,
+            fullscreen: config.fullscreen,
+            display_changed: false,
+            layout: Layout::new(Vector::new(WINDOW_WIDTH, WINDOW_HEIGHT), config.scale_mode),
+            browser_size: None,
+            palettes: Asset::new(load_palettes()),
+            palette: Palette::default(),
+            palette_name: config.palette.clone(),
+            log: MessageLog::new(),
+            show_intents: config.show_intents,
+            intent: None,
+            preview_dir: None,
+            move_preview: None,
+            gamepad_bindings: default_gamepad_bindings(),
+            input_device: InputDevice::Keyboard,
+            held_direction: None,
+            held_time: 0.0,
+            next_repeat_time: 0.0,
+        };
+
+        // generate a run so there is always a level to draw behind the menus
+        game.start_run(seed);
+        game.game_state = GameState::MainMenu;
+
+        Ok(game)
+    }
+
+    /// Process keyboard and mouse, update the game state
+    fn update(&mut self, window: &mut Window) -> Result<()> {
+        self.update_dt = elapsed_secs(&mut self.last_update_time);
+
+        let level = level_of(&self.game_state);
+        self.audio.update(level, self.update_dt);
+
+        self.hurt_time = (self.hurt_time - self.update_dt).max(0.0);
+        self.effects.update(self.update_dt, &self.palette);
+        self.update_display(window);
+        self.update_palette();
+
+        for entity in self.entities.iter_mut() {
+            entity.advance_tweens(self.update_dt);
+        }
+
+        match self.game_state.clone() {
+            GameState::MainMenu | GameState::Paused(_) => {
+                self.update_menu(window);
+            }
+
+            GameState::Options(previous) => {
+                self.update_options(window, *previous);
+            }
+
+            GameState::Controls(previous) => {
+                self.update_controls(window, *previous);
+            }
+
+            GameState::Win => {
+                let stairs_pos = self.entities.iter().find(|ent| {
+                    match ent.typ {
+                        EntityType::Trap(Trap::Win) => true,
+                        _ => false,
+                    }
+                }).unwrap().pos;
+                self.entities[0].pos = stairs_pos;
+
+                self.update_end_screen(window);
+            }
+
+            GameState::NextLevel(n) => {
+                // finish walking onto the stairs first
+                if self.motion_pending() {
+                    return Ok(());
+                }
+
+                if n >= NUM_LEVEL_GAME {
+                    self.game_state = GameState::Win;
+                } else {
+                    self.generate_level(n + 1);
+
+                    self.game_state = GameState::Playing(n + 1);
+                    self.stats.start_level();
+
+                    // each level is its own puzzle, so undo stops at the level start
+                    self.reset_history();
+                }
+            }
+
+            GameState::Playing(n) => {
+                let action = self.read_action(window, &PLAYING_ACTIONS);
+
+                if action == Some(Action::Pause) {
+                    self.game_state = GameState::Paused(n);
+                    self.menu_index = MENU_ITEMS.iter().position(|item| *item == MenuItem::Continue).unwrap();
+                    return Ok(());
+                }
+
+                if action == Some(Action::Undo) {
+                    if self.puzzle_mode {
+                        self.undo();
+                    }
+                    return Ok(());
+                }
+
+                if action == Some(Action::ToggleMute) {
+                    self.audio.toggle_mute();
+                    self.save_config();
+                    return Ok(());
+                }
+
+                if self.update_log(action) {
+                    return Ok(());
+                }
+
+                if action == Some(Action::ToggleIntents) {
+                    self.show_intents = !self.show_intents;
+                    self.save_config();
+                    return Ok(());
+                }
+
+                // holding preview turns direction presses into a look at where the move ends up
+                if self.action_held(window, Action::PreviewMove) {
+                    if let Some(Action::Move(dir)) = action {
+                        self.preview_dir = Some(dir);
+                        return Ok(());
+                    }
+                } else {
+                    self.preview_dir = None;
+                }
+
+                // clicking a tile walks there, one turn at a time
+                if window.mouse()[MouseButton::Left] == ButtonState::Pressed {
+                    self.walk_target = screen_to_map(window.mouse().pos());
+                    self.input_device = InputDevice::Keyboard;
+                }
+
+                self.play_input(action, n);
+            },
+
+            // let the death animation play out before the lose screen
+            GameState::Lost => {
+                self.lose_delay -= self.update_dt;
+                if self.lose_delay <= 0.0 {
+                    self.game_state = GameState::GameOver;
+                }
+            },
+
+            GameState::GameOver => {
+                self.update_end_screen(window);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Process typed text, used for entering a name for the high score table
+    /// and a seed for a seeded run
+    fn event(&mut self, event: &Event, _window: &mut Window) -> Result<()> {
+        if let Some(action) = self.rebinding {
+            if let Event::Key(key, ButtonState::Pressed) = event {
+                if let Some(entry) = self.bindings.iter_mut().find(|(bound_action, _keys)| *bound_action == action) {
+                    entry.1 = vec![*key];
+                }
+                self.rebinding = None;
+                self.rebound_this_frame = true;
+                self.save_config();
+            }
+        }
+
+        if self.entering_seed {
+            match event {
+                Event::Typed(chr) if chr.is_ascii_digit() && self.seed_input.len() < MAX_SEED_DIGITS => {
+                    self.seed_input.push(*chr);
+                },
+
+                Event::Key(Key::Back, ButtonState::Pressed) => {
+                    self.seed_input.pop();
+                },
+
+                _ => (),
+            }
+        }
+
+        // the mouse wheel scrolls the message log whenever the level is shown
+        if let Event::MouseWheel(delta) = event {
+            if !self.in_main_menu() {
+                if delta.y > 0.0 {
+                    self.log.scroll_by(1);
+                } else if delta.y < 0.0 {
+                    self.log.scroll_by(-1);
+                }
+            }
+        }
+
+        if self.entering_name {
+            match event {
+                Event::Typed(chr) if (chr.is_alphanumeric() || *chr == ' ') &&
+                                     self.player_name.chars().count() < MAX_NAME_LENGTH => {
+                    self.player_name.push(*chr);
+                },
+
+                Event::Key(Key::Back, ButtonState::Pressed) => {
+                    self.player_name.pop();
+                },
+
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Draw stuff on the screen
+    fn draw(&mut self, window: &mut Window) -> Result<()> {
+        let draw_dt = elapsed_secs(&mut self.last_draw_time);
+        self.text_cache.sweep();
+
+        window.clear(self.palette.background)?;
+
+        // Draw the game title
+        self.title.execute(|image| {
+            window.draw(
+                &image
+                    .area()
+                    .with_center((WINDOW_WIDTH as i32 / 2, 40)),
+                Img(image),
+            );
+            Ok(())
+        })?;
+
+        // Draw the mononoki font credits
+        self.mononoki_font_info.execute(|image| {
+            window.draw(
+                &image
+                    .area()
+                    .translate((2, WINDOW_HEIGHT as i32 - 60)),
+                Img(image),
+            );
+            Ok(())
+        })?;
+
+        // Draw the Square font credits
+        self.square_font_info.execute(|image| {
+            window.draw(
+                &image
+                    .area()
+                    .translate((2, WINDOW_HEIGHT as i32 - 30)),
+                Img(image),
+            );
+            Ok(())
+        })?;
+
+        // the main menu hides the level
+        if self.in_main_menu() {
+            return self.draw_menu(window);
+        }
+
+        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+        let hud_offset_px = Vector::new(MAP_DRAW_X_OFFSET as u8, MAP_DRAW_Y_OFFSET as u8);
+        // the map shakes, but the health bar and inventory stay put
+        let offset_px = hud_offset_px + self.effects.shake_offset();
+
+        // the hint is looked up before the renderer borrows the font
+        let scroll_hint = self.key_hint(Action::ScrollLogDown);
+        let motion_pending = self.motion_pending();
+
+        let mut renderer = WindowRenderer::new(window, &mut self.char_map, &mut self.animation_library, &mut self.font, &mut self.text_cache);
+
+        // draw map
+        for tile in self.map.iter() {
+            draw_tile(tile, &mut renderer, offset_px, &self.noise, &self.palette)?;
+        }
+
+        // work out what the monsters will do once the player has stopped moving
+        let showing_intent = self.show_intents && !motion_pending &&
+                             match self.game_state { GameState::Playing(_) => true, _ => false };
+        if showing_intent && self.intent.is_none() {
+            self.intent = Some(predict_monsters(&self.entities, &self.map, self.player_id));
+        }
+
+        // draw threat heatmap under the entities
+        if showing_intent {
+            if let Some(intent) = &self.intent {
+                draw_threats(intent, offset_px, &mut renderer, &self.palette);
+            }
+        }
+
+        // draw entities
+        // draw traps
+        for entity in self.entities.iter() {
+            if entity.typ.is_trap() && entity.anim_state.is_none() {
+                let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+                let pos_px = entity.pos.times(tile_size_px);
+                let pos = offset_px + pos_px;
+                draw_entity(entity, pos, &mut renderer, &self.palette)?;
+            }
+        }
+
+        let hurt_flash = self.hurt_time > 0.0 && (self.hurt_time / HURT_FLASH_PERIOD) as i32 % 2 == 0;
+
+        // draw other entities
+        for entity in self.entities.iter_mut() {
+            let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+            let (ent_pos, alpha) = entity.display();
+            let pos_px = ent_pos.times(tile_size_px);
+            let pos = offset_px + pos_px;
+
+            // undiscovered traps do not animate
+            if entity.hidden {
+                draw_entity(entity, pos, &mut renderer, &self.palette)?;
+                continue;
+            }
+
+            // a dead player is drawn by its death animation
+            if entity.typ.is_player() && entity.hp() <= 0 {
+                continue;
+            }
+
+            let color =
+                if entity.typ.is_player() && hurt_flash {
+                    self.palette.hurt.with_alpha(alpha)
+                } else {
+                    self.palette.entity_color(entity).with_alpha(alpha)
+                };
+
+            let kind = SpriteKind::entity(&entity.typ);
+            let anim =
+                match entity.anim_state {
+                    AnimState::None => None,
+
+                    AnimState::Idle(elapsed) => Some((AnimAction::Idle, elapsed)),
+                };
+
+            // pick the frame to show and move the animation along
+            let mut frame = None;
+            if let (Some(kind), Some((action, elapsed))) = (kind, anim) {
+                renderer.animation_library.execute(|library| {
+                    if let Some(sprite_anim) = library.get(kind, action) {
+                        frame = Some(SpriteFrame { kind: kind, action: action, index: sprite_anim.frame_index(elapsed) });
+                        entity.anim_state = AnimState::Idle(sprite_anim.advance(elapsed, draw_dt).unwrap_or(0.0));
+                    }
+                    return Ok(());
+                })?;
+            }
+
+            let drawn =
+                match frame {
+                    Some(frame) => renderer.draw_sprite(pos, frame, color)?,
+                    None => false,
+                };
+
+            // anything without an animation is drawn as its glyph
+            if !drawn {
+                draw_entity(entity, pos, &mut renderer, &self.palette)?;
+            }
+
+            if self.palette.outlines {
+                draw_outline(entity, pos, alpha, &mut renderer, &self.palette);
+            }
+
+            draw_entity_status(entity, pos, alpha, &mut renderer, &self.palette)?;
+        }
+
+        // draw monster moves and attacks over the entities
+        if showing_intent {
+            if let Some(intent) = &self.intent {
+                draw_intent(intent, offset_px, &mut renderer, &self.palette);
+            }
+        }
+
+        // draw where the previewed move would end up, from a held key or the step
+        // that clicking the tile under the mouse would take
+        let playing = match self.game_state { GameState::Playing(_) => true, _ => false };
+        if playing && !motion_pending {
+            let player_pos = self.entities[self.player_id].pos;
+            let mouse_pos = renderer.window.mouse().pos();
+            let (map, entities) = (&self.map, &self.entities);
+            let preview_dir =
+                self.preview_dir.or_else(|| {
+                    screen_to_map(mouse_pos)
+                        .and_then(|tile_pos| walk_step(player_pos, tile_pos, map, entities))
+                });
+
+            match preview_dir {
+                Some(dir) => {
+                    if self.move_preview.as_ref().map(|preview| preview.dir) != Some(dir) {
+                        self.move_preview = preview_move(&self.entities, &self.map, self.player_id, dir);
+                    }
+                },
+
+                None => self.move_preview = None,
+            }
+
+            if let Some(preview) = &self.move_preview {
+                draw_move_preview(preview, offset_px, &mut renderer, &self.palette)?;
+            }
+        }
+
+        // draw animations
+        let player_color = self.palette.player;
+        let monster_color = self.palette.monster;
+        let mut animations_done = Vec::new();
+        for (animation_index, animation) in self.animations.iter_mut().enumerate() {
+            // attacks replace the attacker's own sprite, so its tile is drawn over it first
+            let (kind, action, loc, color, cover_tile, elapsed) =
+                match animation {
+                    Animation::MonsterDeath(monster_typ, loc, elapsed) =>
+                        (SpriteKind::monster(*monster_typ), AnimAction::Die, *loc, monster_color, false, elapsed),
+
+                    Animation::MonsterAttack(monster_typ, loc, direction, elapsed) =>
+                        (SpriteKind::monster(*monster_typ), AnimAction::attack(*direction), *loc, monster_color, true, elapsed),
+
+                    Animation::PlayerDeath(loc, elapsed) =>
+                        (SpriteKind::Player, AnimAction::Die, *loc, player_color, false, elapsed),
+                };
+
+            let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+            let pos_px = loc.times(tile_size_px);
+            let pos = offset_px + pos_px;
+
+            if cover_tile {
+                if let Some(tile) = self.map.iter().find(|other_tile| other_tile.pos == loc) {
+                    draw_tile(tile, &mut renderer, offset_px, &self.noise, &self.palette)?;
+                }
+            }
+
+            let mut frame = None;
+            let mut finished = false;
+            renderer.animation_library.execute(|library| {
+                if let Some(sprite_anim) = library.get(kind, action) {
+                    frame = Some(SpriteFrame { kind: kind, action: action, index: sprite_anim.frame_index(*elapsed) });
+
+                    match sprite_anim.advance(*elapsed, draw_dt) {
+                        Some(next) => *elapsed = next,
+                        None => finished = true,
+                    }
+                } else {
+                    finished = true;
+                }
+                return Ok(());
+            })?;
+
+            if let Some(frame) = frame {
+                renderer.draw_sprite(pos, frame, color)?;
+            }
+
+            if finished {
+                animations_done.push(animation_index);
+            }
+        }
+
+        for animation_index in animations_done.iter().rev() {
+            self.animations.remove(*animation_index);
+        }
+
+        self.effects.draw(&mut renderer, offset_px, self.layout.area(), &self.palette);
+
+
+        let player = &self.entities[self.player_id];
+        let full_health_width_px = 100.0;
+        let current_health_width_px =
+            (player.hp() as f32 / player.max_hp() as f32) * full_health_width_px;
+
+        let map_size_px = self.map_size.times(tile_size_px);
+        let health_bar_pos_px = hud_offset_px + Vector::new(map_size_px.x, 0.0);
+
+        // Full health
+        renderer.draw_rect(
+            Rectangle::new(health_bar_pos_px, (full_health_width_px, tile_size_px.y)),
+            self.palette.health.with_alpha(0.5),
+        );
+
+        // Current health
+        renderer.draw_rect(
+            Rectangle::new(health_bar_pos_px, (current_health_width_px, tile_size_px.y)),
+            self.palette.health,
+        );
+
+        // Current health
+        let inventory_window = &mut renderer.window;
+        self.inventory.execute(|image| {
+            inventory_window.draw(
+                &image
+                    .area()
+                    .translate(health_bar_pos_px + Vector::new(0, tile_size_px.y)),
+                Img(image),
+            );
+            Ok(())
+        })?;
+
+        if self.puzzle_mode {
+            let puzzle_text = format!("Puzzle Mode - Undos: {}", self.undo_count);
+            renderer.draw_text(&puzzle_text,
+                               health_bar_pos_px + Vector::new(0, 2 * tile_size_px.y as u32),
+                               20.0,
+                               self.palette.text)?;
+        }
+
+        // message log, to the left of the map
+        let log_pos_px = Vector::new(10, MAP_DRAW_Y_OFFSET as u32);
+        for (line_index, line) in self.log.visible_lines().iter().enumerate() {
+            renderer.draw_text(line,
+                               log_pos_px + Vector::new(0.0, line_index as f32 * LOG_LINE_HEIGHT_PX),
+                               LOG_TEXT_SIZE,
+                               self.palette.text)?;
+        }
+        if self.log.scroll > 0 {
+            renderer.draw_text(&format!("{} more lines ({})", self.log.scroll, scroll_hint),
+                               log_pos_px + Vector::new(0.0, LOG_VISIBLE_LINES as f32 * LOG_LINE_HEIGHT_PX),
+                               LOG_TEXT_SIZE,
+                               self.palette.text_disabled)?;
+        }
+
+        if let GameState::Playing(_) = self.game_state {
+            let mouse_pos = renderer.window.mouse().pos();
+            if let Some(tile_pos) = screen_to_map(mouse_pos) {
+                let description = describe_tile(tile_pos, &self.map, &self.entities);
+                draw_tooltip(&description, mouse_pos + Vector::new(16, 16), &mut renderer, &self.palette)?;
+            }
+        }
+
+        // Draw Message
+        if self.game_state == GameState::GameOver {
+            self.lost_game_message.execute(|image| {
+                window.draw(
+                    &image
+                        .area()
+                        .translate((MAP_DRAW_X_OFFSET as u16 + 40, WINDOW_HEIGHT as u16 - 100)),
+                    Img(image),
+                );
+                Ok(())
+            })?;
+        }
+
+        if self.game_state == GameState::Win {
+            self.win_game_message.execute(|image| {
+                window.draw(
+                    &image
+                        .area()
+                        .translate((MAP_DRAW_X_OFFSET as u16 + 40, WINDOW_HEIGHT as u16 - 100)),
+                    Img(image),
+                );
+                Ok(())
+            })?;
+        }
+
+        if self.game_state == GameState::GameOver || self.game_state == GameState::Win {
+            self.draw_end_screen(window)?;
+        }
+
+        match self.game_state {
+            GameState::Paused(_) | GameState::Options(_) | GameState::Controls(_) => self.draw_menu(window)?,
+            _ => (),
+        }
+
+        //let mut rng = thread_rng();
+        //for entity in self.entities.iter_mut() {
+        //    if (entity.typ.is_monster() || entity.typ.is_player()) &&
+        //        entity.idle == None &&
+        //        rng.gen_range(0.0, 1.0) < IDLE_PROB {
+
+        //        entity.idle = AnimState::Idle(0);
+        //    }
+        //}
+
+        Ok(())
+    }
+}
+
+/// The closest of the four arrow directions, favoring left and right for diagonals
+fn direction(dir: Vector) -> Arrow {
+    if dir.x.abs() >= dir.y.abs() && dir.x > 0.0 {
+        Arrow::Right
+    } else if dir.x.abs() >= dir.y.abs() && dir.x < 0.0 {
+        Arrow::Left
+    } else if dir.y < 0.0 {
+        Arrow::Up
+    } else {
+        Arrow::Down
+    }
+}
+
+// Update Functions
+fn monster_take_turn(entities: &mut [Entity],
+                     map: &Map,
+                     player_id: EntityId,
+                     index: EntityId,
+                     attacks: &mut Vec<(EntityId, EntityId, Arrow)>) {
+    let player = entities[player_id].clone();
+    // NOTE copies all entities every monster turn!
+    let entities_clone = entities.to_vec();
+
+    let monster = &mut entities[index];
+    let prev_position = monster.pos;
+
+    let pos_diff = player.pos - monster.pos;
+    let mut pos_move = monster.pos;
+    pos_move.x += pos_diff.x.abs().signum() * pos_diff.x.signum();
+    pos_move.y += pos_diff.y.abs().signum() * pos_diff.y.signum();
+    // attempt to constrain rooks to lane movement.
+    if monster.typ.is_rook() && pos_move.x.abs() == pos_move.y.abs() {
+        if pos_diff.x.abs() > pos_diff.y.abs() && !blocked_tile(pos_move, map) {
+            pos_move.y = 0.0;
+        } else {
+            pos_move.x = 0.0;
+        }
+    }
+
+    if blocked_tile(pos_move, map) {
+        pos_move = prev_position;
+    } else if let Some(entity) = occupied_tile(pos_move, &entities_clone) {
+        if entity.typ.is_player() {
+            let dir = direction(pos_move - prev_position);
+            pos_move = prev_position;
+            attacks.push((index, player_id, dir));
+        }  else if entity.typ.is_monster() {
+            // TODO add for monsters too...
+            pos_move = prev_position;
+        }
+    }
+
+    monster.pos = pos_move;
+}
+
+/// Let every monster act for one player turn, returning the attacks they make.
+/// Only the monsters' positions and energy change, so this can also be run
+/// on a copy of the entities to see what the monsters are about to do.
+fn monster_turns(entities: &mut [Entity],
+                 map: &Map,
+                 player_id: EntityId,
+                 events: &mut Vec<GameEvent>) -> Vec<(EntityId, EntityId, Arrow)> {
+    // the player's action took ACTION_ENERGY worth of the player's time, so
+    // each monster gains energy in proportion to its speed relative to the player.
+    let player_speed = entities[player_id].speed();
+    for monster in entities.iter_mut().filter(|entity| entity.typ.is_monster() && entity.hp() > 0) {
+        monster.energy += monster.speed() * ACTION_ENERGY / player_speed;
+    }
+
+    let mut attacks: Vec<(EntityId, EntityId, Arrow)> = Vec::new();
+
+    // monsters act in rounds, in entity order, until none can afford another action.
+    // this keeps the outcome of a turn independent of frame timing.
+    loop {
+        let ready: Vec<EntityId> =
+            entities.iter()
+                    .enumerate()
+                    .filter(|(_index, entity)| entity.typ.is_monster() && entity.hp() > 0 && entity.energy >= ACTION_ENERGY)
+                    .map(|(index, _entity)| index)
+                    .collect();
+
+        if ready.is_empty() {
+            break;
+        }
+
+        for index in ready {
+            entities[index].energy -= ACTION_ENERGY;
+            let from = entities[index].pos;
+            monster_take_turn(entities, map, player_id, index, &mut attacks);
+
+            let monster = &entities[index];
+            if monster.pos != from {
+                events.push(GameEvent::Moved { typ: monster.typ, from: from, to: monster.pos });
+            }
+        }
+    }
+
+    return attacks;
+}
+
+/// What the monsters will do next turn if the player waits, and how many
+/// monsters would attack the player on each tile the player could move to
+#[derive(Clone, Debug, PartialEq)]
+struct MonsterIntent {
+    steps: Vec<(Vector, Vector)>,
+    // where each attack comes from and where it lands
+    attacks: Vec<(Vector, Vector)>,
+    threats: Vec<(Vector, usize)>,
+}
+
+fn predict_monsters(entities: &[Entity], map: &Map, player_id: EntityId) -> MonsterIntent {
+    let mut predicted = entities.to_vec();
+    let mut events = Vec::new();
+    let attacks = monster_turns(&mut predicted, map, player_id, &mut events);
+
+    let steps =
+        events.iter().filter_map(|event| {
+            match event {
+                GameEvent::Moved { from, to, .. } => Some((*from, *to)),
+                _ => None,
+            }
+        }).collect();
+
+    let attacks =
+        attacks.iter()
+               .map(|(attacker, target, _dir)| (predicted[*attacker].pos, predicted[*target].pos))
+               .collect();
+
+    // try each tile the player could end their turn on, including staying put
+    let player_pos = entities[player_id].pos;
+    let mut threats = Vec::new();
+    let offsets = DIRECTIONS.iter().map(|dir| dir.offset()).chain(std::iter::once(Vector::new(0, 0)));
+    for offset in offsets {
+        let pos = player_pos + offset;
+        if blocked_tile(pos, map) {
+            continue;
+        }
+
+        let mut predicted = entities.to_vec();
+        predicted[player_id].pos = pos;
+        let attacks = monster_turns(&mut predicted, map, player_id, &mut Vec::new());
+        if !attacks.is_empty() {
+            threats.push((pos, attacks.len()));
+        }
+    }
+
+    return MonsterIntent {
+        steps: steps,
+        attacks: attacks,
+        threats: threats,
+    };
+}
+
+/// Where a move would take the player once the monsters and traps have had their turn
+#[derive(Clone, Debug, PartialEq)]
+struct MovePreview {
+    dir: Direction,
+    // each leg of the player's motion, and whether it was a teleport
+    path: Vec<(Vector, Vector, bool)>,
+    landing: Vector,
+    // traps set off on the way, and whether each was reached by a chain
+    traps: Vec<(Vector, bool)>,
+    // tiles a bump trap could throw the player onto. The roll is left to
+    // chance, so the preview stops at the bump trap when there are any.
+    bump_landings: Vec<Vector>,
+    damage: i32,
+    dies: bool,
+}
+
+/// Play the move out on a copy of the level. If traps leave the player on
+/// another trap, that trap is followed as well, as if the player waited there.
+fn preview_move(entities: &[Entity], map: &Map, player_id: EntityId, dir: Direction) -> Option<MovePreview> {
+    let target = entities[player_id].pos + dir.offset();
+
+    // walls stop the move entirely
+    if blocked_tile(target, map) {
+        return None;
+    }
+
+    // hidden traps are left out, so the preview only gives away what the player can see
+    let mut predicted: Vec<Entity> = entities.iter().filter(|entity| !entity.hidden).cloned().collect();
+    let player_id = predicted.iter().position(|entity| entity.typ.is_player()).unwrap_or(player_id);
+    // the game's own rng would give away where bump traps land, so the
+    // simulation rolls on a fixed one and bump landings are listed instead
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut events = Vec::new();
+    let mut path = Vec::new();
+    let mut traps = Vec::new();
+    let mut bump_landings = Vec::new();
+    let mut visited = vec![target];
+    predicted[player_id].pos = target;
+
+    for turn in 0..MAX_PREVIEW_TURNS {
+        let first_event = events.len();
+        let mut next_level = false;
+        let mut win = false;
+
+        monster_phase(&mut predicted, map, player_id, &mut events);
+        resolve_traps(&mut predicted, map, &mut rng, &mut events, &mut next_level, &mut win);
+
+        for event in events[first_event..].iter() {
+            if let GameEvent::TrapTriggered(trigger) = event {
+                if trigger.typ.is_player() && trigger.trap == Trap::Bump {
+                    predicted[player_id].pos = trigger.pos;
+                    bump_landings = bump_destinations(trigger.pos, map);
+                }
+            }
+        }
+
+        // the player's tweens are the path the move would be shown taking
+        let player = &mut predicted[player_id];
+        player.tween_to_pos();
+        for tween in player.tweens.iter() {
+            match tween {
+                Tween::Step(from, to) | Tween::Slide(from, to) => path.push((*from, *to, false)),
+
+                Tween::Teleport(from, to) => path.push((*from, *to, true)),
+
+                Tween::Shake(_) => (),
+            }
+        }
+        player.snap_to_pos();
+
+        for event in events[first_event..].iter() {
+            if let GameEvent::TrapTriggered(trigger) = event {
+                if trigger.typ.is_player() {
+                    traps.push((trigger.pos, turn > 0));
+                }
+            }
+        }
+
+        let pos = predicted[player_id].pos;
+        if predicted[player_id].hp() <= 0 || next_level || win || !bump_landings.is_empty() ||
+           trap_tile(pos, &predicted).is_none() || visited.contains(&pos) {
+            break;
+        }
+        visited.push(pos);
+    }
+
+    let damage =
+        events.iter().map(|event| {
+            match event {
+                GameEvent::Damaged { typ, amount, .. } if typ.is_player() => *amount,
+                _ => 0,
+            }
+        }).sum();
+
+    return Some(MovePreview {
+        dir: dir,
+        path: path,
+        landing: predicted[player_id].pos,
+        traps: traps,
+        bump_landings: bump_landings,
+        damage: damage,
+        dies: predicted[player_id].hp() <= 0,
+    });
+}
+
+/// Every tile a bump trap at the given position could knock something onto
+fn bump_destinations(pos: Vector, map: &Map) -> Vec<Vector> {
+    let mut destinations = Vec::new();
+    for y_offset in -1..=1 {
+        for x_offset in -1..=1 {
+            let destination = attempt_move(pos, Vector::new(x_offset, y_offset), map);
+            if !destinations.contains(&destination) {
+                destinations.push(destination);
+            }
+        }
+    }
+
+    return destinations;
+}
+
+/// The monsters' half of a turn: they move and attack, and the dead are removed
+fn monster_phase(entities: &mut Vec<Entity>, map: &Map, player_id: EntityId, events: &mut Vec<GameEvent>) {
+    let attacks = monster_turns(entities, map, player_id, events);
+
+    // resolve attacks that occured
+    for attack in attacks.iter() {
+        let attacker = entities[attack.0].clone();
+        events.push(GameEvent::Attacked {
+            attacker: attacker.typ,
+            pos: attacker.pos,
+            target: entities[attack.1].typ,
+            direction: attack.2,
+        });
+        deal_damage(&mut entities[attack.1], 1, Cause::Attack(attacker.typ), events);
+    }
+
+    entities.retain(|entity| !(entity.typ.is_monster() && entity.hp() <= 0));
+}
+
+fn lerp_color(src: Color, dst: Color, amount: f32) -> Color {
+    return Color {
+        r: lerp(src.r, dst.r, amount),
+        g: lerp(src.g, dst.g, amount),
+        b: lerp(src.b, dst.b, amount),
+        a: lerp(src.a, dst.a, amount),
+    };
+}
+
+fn attempt_move(pos: Vector, offset: Vector, map: &Map) -> Vector {
+    let mut new_pos = pos + offset;
+
+    if blocked_tile(new_pos, map) {
+        new_pos = pos;
+    }
+
+    return new_pos;
+}
+
+/// The level being played, looking through any menus on top of it
+fn level_of(game_state: &GameState) -> Option<usize> {
+    return match game_state {
+        GameState::Playing(n) | GameState::Paused(n) | GameState::NextLevel(n) => Some(*n),
+        GameState::Options(previous) | GameState::Controls(previous) => level_of(previous),
+        _ => None,
+    };
+}
+
+fn random_seed() -> u64 {
+    return thread_rng().gen::<u32>() as u64;
+}
+
+/// One turn of the game rules: the player acts, then the monsters, then the traps.
+/// Returns whether the player's action used up a turn. Shared by every frontend.
+#[allow(clippy::too_many_arguments)]
+fn play_turn<R: Rng>(entities: &mut Vec<Entity>,
+                     map: &Map,
+                     rng: &mut R,
+                     player_id: EntityId,
+                     action: Action,
+                     events: &mut Vec<GameEvent>,
+                     next_level: &mut bool,
+                     win: &mut bool) -> bool {
+    if !update_player(entities, map, player_id, action, events) {
+        return false;
+    }
+
+    monster_phase(entities, map, player_id, events);
+
+    resolve_traps(entities, map, rng, events, next_level, win);
+
+    let player_pos = entities[player_id].pos;
+    reveal_traps(entities, player_pos, TRAP_DETECTION_RADIUS);
+
+    return true;
+}
+
+/// Apply the player's action, returning whether it used up the player's turn
+fn update_player(entities: &mut [Entity], map: &Map, player_id: EntityId, action: Action, events: &mut Vec<GameEvent>) -> bool {
+    let mut took_turn: bool = false;
+
+    let player = &mut entities[player_id];
+    let previous_pos = player.pos;
+    match action {
+        Action::Move(dir) => {
+            let offset = dir.offset();
+            player.pos.x = clamp(0.0, MAP_WIDTH as f32, player.pos.x + offset.x);
+            player.pos.y = clamp(0.0, MAP_HEIGHT as f32, player.pos.y + offset.y);
+            took_turn = true;
+        },
+
+        Action::Wait => {
+            took_turn = true;
+        },
+
+        // there are no items or objects to interact with yet
+        Action::Interact | Action::UseItem => {
+        },
+
+        _ => (),
+    }
+
+    if blocked_tile(player.pos, map) {
+        player.pos = previous_pos;
+        took_turn = false;
+    }
 
-            return Ok(rook_death);
-        }));
+    if player.pos != previous_pos {
+        let moved = GameEvent::Moved { typ: player.typ, from: previous_pos, to: player.pos };
+        events.push(moved);
+    }
 
-        let lost_game_message = Asset::new(Font::load(font_mononoki).and_then(|font| {
-            font.render("You Lose!", &FontStyle::new(72.0, WHITE))
-        }));
+    return took_turn;
+}
 
-        let win_game_message = Asset::new(Font::load(font_mononoki).and_then(|font| {
-            font.render("You Win!", &FontStyle::new(72.0, WHITE))
-        }));
+fn reveal_traps(entities: &mut [Entity], pos: Vector, radius: f32) {
+    for entity in entities.iter_mut() {
+        if entity.typ.is_trap() && magnitude(entity.pos - pos) <= radius {
+            entity.hidden = false;
+        }
+    }
+}
 
-        let mononoki_font_info = Asset::new(Font::load(font_mononoki).and_then(|font| {
-            font.render(
-                "",
-                &FontStyle::new(20.0, WHITE),
-            )
-        }));
+fn resolve_traps<R: Rng>(entities: &mut Vec<Entity>,
+                         map: &Map,
+                         rng: &mut R,
+                         events: &mut Vec<GameEvent>,
+                         next_level: &mut bool,
+                         win: &mut bool) {
+    let entities_clone = entities.clone();
+    let mut removals: Vec<usize> = Vec::new();
+    let mut reveals: Vec<usize> = Vec::new();
+    let mut moves: Vec<(Vector, usize)> = Vec::new();
+    let mut count_downs: Vec<(usize, u8)> = Vec::new();
 
-        let square_font_info = Asset::new(Font::load(font_mononoki).and_then(move |font| {
-            font.render(
-                "A Ludum Dare Game by Joel and Noah Ryan",
-                &FontStyle::new(20.0, WHITE),
-            )
-        }));
+    let trap_iter =
+        entities.iter_mut()
+                .enumerate()
+                .filter(|(_index, ent)| ent.typ.is_player() || ent.typ.is_monster());
+    for (index, entity) in trap_iter {
+        if let Some(trap_entity) = trap_tile(entity.pos, &entities_clone) {
+            let trap_index = entities_clone.iter().position(|other| *other == trap_entity).unwrap();
+            // anything that steps on a trap gives away its location
+            reveals.push(trap_index);
+            let trap_pos = entity.pos;
+
+            // the trigger goes before anything the trap causes, and is
+            // filled in with where the entity ended up once the trap is done
+            let trigger_index = events.len();
+            if let EntityType::Trap(trap) = trap_entity.typ {
+                events.push(GameEvent::TrapTriggered(TrapTrigger {
+                    trap: trap,
+                    typ: entity.typ,
+                    pos: trap_pos,
+                    destination: trap_pos,
+                    delay: entity.time_until_shown(),
+                }));
+            }
 
-        // TODO inventory message is here.
-        let inventory = Asset::new(Font::load(font_mononoki).and_then(move |font| {
-            font.render(
-                "",
-                &FontStyle::new(20.0, WHITE),
-            )
-        }));
+            match trap_entity.typ {
+                EntityType::Trap(trap) => {
+                    match trap {
+                        Trap::Berserk => {
+                            match &mut entity.typ {
+                                EntityType::Monster(monster) => {
+                                    monster.status = Some(Status::Berserk);
+                                },
 
-        let map_size = Vector::new(MAP_WIDTH as u8, MAP_HEIGHT as u8);
-        let mut map = generate_map(map_size);
-        let player_id = 0;
+                                EntityType::Player(player) => {
+                                    player.status = Some(Status::Berserk);
+                                },
+
+                                _ => panic!("Unexpected entity type!"),
+                            }
+                        },
+
+                        Trap::Kill => {
+                            deal_damage(entity, 5, Cause::Trap(trap), events);
+                            removals.push(trap_index);
+                        },
+
+                        Trap::Teleport => {
+                            // find next teleport. if one is find, move character there.
+                            let entities_len = entities_clone.len();
+                            for other_index in 0..entities_len {
+                                let offset_index = (other_index + trap_index + 1) % entities_len;
+                                let other_entity = &entities_clone[offset_index];
+                                if let EntityType::Trap(Trap::Teleport) = other_entity.typ {
+                                    let from = entity.pos;
+                                    entity.tween_to_pos();
+                                    entity.pos = other_entity.pos;
+                                    entity.push_tween(Tween::Teleport(from, other_entity.pos));
+                                    break;
+                                }
+                            }
+                        },
+
+                        Trap::Bump => {
+                            let pos = entity.pos;
+
+                            // shake on the trap, then get knocked off of it
+                            entity.tween_to_pos();
+                            entity.push_tween(Tween::Shake(pos));
+
+                            let x_offset = rng.gen_range(-1, 2);
+                            let y_offset = rng.gen_range(-1, 2);
+                            entity.pos =
+                                attempt_move(pos,
+                                             Vector::new(x_offset, y_offset),
+                                             map);
+                        }
+
+                        Trap::CountDown(n) => {
+                            if n == 0 {
+                                deal_damage(entity, 5, Cause::Trap(trap), events);
+                            } else {
+                                count_downs.push((trap_index, n - 1));
+                            }
+                        },
+
+                        Trap::NextLevel => {
+                            if entity.typ.is_player() {
+                                *next_level = true;
+                            }
+                        }
+
+                        Trap::Win => {
+                            if entity.typ.is_player() {
+                                *win = true;
+                            }
+                        }
+
+                        Trap::Arrow(dir) => {
+                            let x_dir;
+                            let y_dir;
+                            match dir {
+                                Arrow::Left => {
+                                    x_dir = -1;
+                                    y_dir = 0;
+                                },
+
+                                Arrow::Right => {
+                                    x_dir = 1;
+                                    y_dir = 0;
+                                },
+
+                                Arrow::Up => {
+                                    x_dir = 0;
+                                    y_dir = -1;
+                                },
+
+                                Arrow::Down => {
+                                    x_dir = 0;
+                                    y_dir = 1;
+                                },
+                            }
+
+                            let mut cur_pos = entity.pos;
+                            let mut prev_pos = entity.pos;
+                            cur_pos += Vector::new(x_dir, y_dir);
+                            while !blocked_tile(cur_pos, map) &&
+                                  occupied_tile(cur_pos, &entities_clone).is_none() {
+                                prev_pos = cur_pos;
+                                cur_pos += Vector::new(x_dir, y_dir);
+                            }
+                            if occupied_tile(cur_pos, &entities_clone).map(|ent| ent.typ.is_trap()).unwrap_or(false) {
+                                prev_pos = cur_pos;
+                            }
+
+                            moves.push((prev_pos, index));
+                        }
+                    }
+                },
+
+                _ => panic!("Unreachable?"),
+            }
+
+            if let GameEvent::TrapTriggered(trigger) = &mut events[trigger_index] {
+                trigger.typ = entity.typ;
+                trigger.destination = entity.pos;
+            }
+        }
+    }
+
+
+    // arrow traps slide entities along one tile at a time
+    for (pos, index) in moves {
+        let entity = &mut entities[index];
+        entity.tween_to_pos();
+
+        let step = sign(pos - entity.pos);
+        let mut slide_pos = entity.pos;
+        while slide_pos != pos {
+            let next_pos = slide_pos + step;
+            entity.push_tween(Tween::Slide(slide_pos, next_pos));
+            slide_pos = next_pos;
+        }
+
+        entity.pos = pos;
+    }
+
+    for ix in reveals {
+        entities[ix].hidden = false;
+    }
+
+    for (ix, new_n) in count_downs.iter() {
+        entities[*ix].typ = EntityType::Trap(Trap::CountDown(*new_n));
+        entities[*ix].glyph = (b'0' + *new_n) as char;
+    }
+
+    removals.sort();
+    removals.reverse();
+    for index in removals.iter() {
+        entities.swap_remove(*index);
+    }
+}
+
+/// Floors are shaded with noise over where they are drawn on the screen
+fn tile_color(tile: &Tile, pos_px: Vector, noise: &Perlin, palette: &Palette) -> Color {
+    if tile.blocks {
+        return palette.wall;
+    }
+
+    let color_noise =
+        noise.get([6.0 * (pos_px.x as f64 / WINDOW_WIDTH as f64),
+                   6.0 * (pos_px.y as f64 / WINDOW_HEIGHT as f64)]);
+    return lerp_color(palette.floor_dark, palette.floor_light, color_noise as f32);
+}
+
+fn draw_tile<R: Renderer>(tile: &Tile,
+                          renderer: &mut R,
+                          offset_px: Vector,
+                          noise: &Perlin,
+                          palette: &Palette) -> Result<()> {
+    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+    let pos_px = tile.pos.times(tile_size_px);
+    let pos = offset_px + pos_px;
+    let tile_color = tile_color(tile, pos, noise, palette);
+
+    return renderer.draw_glyph(pos, tile.glyph, tile_color);
+}
+
+fn draw_entity<R: Renderer>(entity: &Entity,
+                            pos: Vector,
+                            renderer: &mut R,
+                            palette: &Palette) -> Result<()> {
+    // an undiscovered trap looks like plain floor, which is already drawn
+    if entity.hidden {
+        return Ok(());
+    }
+
+    // Berserk is shown by its status icon, see draw_entity_status
+    return renderer.draw_glyph(pos, entity.glyph, palette.entity_color(entity));
+}
+
+/// Shapes that tell traps and monsters apart without relying on color:
+/// a square frame around traps and a round dot beside monsters
+fn draw_outline<R: Renderer>(entity: &Entity, pos: Vector, alpha: f32, renderer: &mut R, palette: &Palette) {
+    let glyph_center_px = pos + Vector::new(8, 8);
+    let color = palette.entity_color(entity).with_alpha(alpha);
+    match entity.typ {
+        EntityType::Trap(_) => {
+            draw_frame(glyph_center_px, TILE_WIDTH_PX as f32 - 2.0, color, renderer);
+        },
+
+        EntityType::Monster(_) => {
+            renderer.draw_circle(glyph_center_px - Vector::new(13, 13), 4.0, color);
+        },
+
+        _ => (),
+    }
+}
+
+/// Shade each tile the player could move to by how many monsters would attack there
+fn draw_threats<R: Renderer>(intent: &MonsterIntent, offset_px: Vector, renderer: &mut R, palette: &Palette) {
+    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+    for (pos, count) in intent.threats.iter() {
+        let alpha = (THREAT_ALPHA * *count as f32).min(INTENT_ALPHA);
+        // tiles are drawn centered 8 pixels in from their position, like the glyphs
+        let center = offset_px + pos.times(tile_size_px) + Vector::new(8, 8);
+        let rect = Rectangle::new(center - tile_size_px * 0.5, tile_size_px);
+        renderer.draw_rect(rect, palette.hurt.with_alpha(alpha));
+    }
+}
+
+/// Arrows for each monster step, and a marked arrow for each attack
+fn draw_intent<R: Renderer>(intent: &MonsterIntent, offset_px: Vector, renderer: &mut R, palette: &Palette) {
+    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+    let center = |pos: Vector| offset_px + pos.times(tile_size_px) + Vector::new(8, 8);
+
+    for (from, to) in intent.steps.iter() {
+        draw_arrow(center(*from), center(*to), palette.monster.with_alpha(INTENT_ALPHA), renderer);
+    }
+
+    for (from, to) in intent.attacks.iter() {
+        let color = palette.hurt.with_alpha(INTENT_ALPHA);
+        renderer.draw_circle(center(*from), 3.0, color);
+        draw_arrow(center(*from), center(*to), color, renderer);
+    }
+}
+
+/// The path of a previewed move, a frame on the landing tile and on each trap
+/// it sets off, and the damage the player would take
+fn draw_move_preview<R: Renderer>(preview: &MovePreview, offset_px: Vector, renderer: &mut R, palette: &Palette) -> Result<()> {
+    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+    let center = |pos: Vector| offset_px + pos.times(tile_size_px) + Vector::new(8, 8);
+    let path_color = palette.player.with_alpha(PREVIEW_ALPHA);
+
+    for (from, to, teleport) in preview.path.iter() {
+        if *teleport {
+            renderer.draw_circle(center(*from), 3.0, path_color);
+            renderer.draw_circle(center(*to), 3.0, path_color);
+        } else {
+            draw_arrow(center(*from), center(*to), path_color, renderer);
+        }
+    }
+
+    for (pos, chained) in preview.traps.iter() {
+        let alpha = if *chained { CHAIN_ALPHA } else { PREVIEW_ALPHA };
+        draw_frame(center(*pos), TILE_WIDTH_PX as f32 - 6.0, palette.trap.with_alpha(alpha), renderer);
+    }
+
+    // a bump trap could leave the player on any of several tiles
+    for pos in preview.bump_landings.iter() {
+        draw_frame(center(*pos), TILE_WIDTH_PX as f32 - 12.0, path_color, renderer);
+    }
+
+    let landing_px = center(preview.landing);
+    draw_frame(landing_px, TILE_WIDTH_PX as f32, path_color, renderer);
+
+    if preview.dies {
+        renderer.draw_text("Fatal", landing_px + Vector::new(10, -24), LOG_TEXT_SIZE, palette.hurt)?;
+    } else if preview.damage > 0 {
+        let text = format!("-{} hp", preview.damage);
+        renderer.draw_text(&text, landing_px + Vector::new(10, -24), LOG_TEXT_SIZE, palette.hurt)?;
+    } else if !preview.bump_landings.is_empty() {
+        renderer.draw_text("Random", landing_px + Vector::new(10, -24), LOG_TEXT_SIZE, palette.text)?;
+    }
+
+    return Ok(());
+}
+
+/// A line from one tile centre toward another, with a head stopping short of the end
+fn draw_arrow<R: Renderer>(start: Vector, end: Vector, color: Color, renderer: &mut R) {
+    let dir = (end - start).normalize();
+    let tip = end - dir * 4.0;
+    renderer.draw_line(start, tip, INTENT_WIDTH_PX, color);
+
+    let side = Vector::new(-dir.y, dir.x);
+    let back = tip - dir * 4.0;
+    renderer.draw_line(tip, back + side * 3.0, INTENT_WIDTH_PX, color);
+    renderer.draw_line(tip, back - side * 3.0, INTENT_WIDTH_PX, color);
+}
+
+/// A square outline of the given size centered on a point
+fn draw_frame<R: Renderer>(center: Vector, size: f32, color: Color, renderer: &mut R) {
+    let width = OUTLINE_WIDTH_PX;
+    let top_left = center - Vector::new(size / 2.0, size / 2.0);
+    renderer.draw_rect(Rectangle::new(top_left, (size, width)), color);
+    renderer.draw_rect(Rectangle::new(top_left + Vector::new(0.0, size - width), (size, width)), color);
+    renderer.draw_rect(Rectangle::new(top_left, (width, size)), color);
+    renderer.draw_rect(Rectangle::new(top_left + Vector::new(size - width, 0.0), (width, size)), color);
+}
+
+/// A health bar above each monster and an icon for any status effect.
+/// The player's health is shown in the HUD instead.
+fn draw_entity_status<R: Renderer>(entity: &Entity, pos: Vector, alpha: f32, renderer: &mut R, palette: &Palette) -> Result<()> {
+    if !(entity.typ.is_monster() || entity.typ.is_player()) {
+        return Ok(());
+    }
+
+    // glyphs are scaled up around the center of their 16 pixel square
+    let glyph_center_px = pos + Vector::new(8, 8);
+    let glyph_half_size_px = 8.0 * SCALE;
+
+    if entity.typ.is_monster() {
+        let bar_pos = glyph_center_px - Vector::new(ENTITY_BAR_WIDTH_PX / 2.0, glyph_half_size_px + ENTITY_BAR_HEIGHT_PX);
+        let health_width_px = (entity.hp().max(0) as f32 / entity.max_hp() as f32) * ENTITY_BAR_WIDTH_PX;
+        renderer.draw_rect(Rectangle::new(bar_pos, (ENTITY_BAR_WIDTH_PX, ENTITY_BAR_HEIGHT_PX)),
+                           palette.health.with_alpha(0.5 * alpha));
+        renderer.draw_rect(Rectangle::new(bar_pos, (health_width_px, ENTITY_BAR_HEIGHT_PX)),
+                           palette.health.with_alpha(alpha));
+    }
+
+    if let Some(status) = entity.status() {
+        let icon_pos = glyph_center_px + Vector::new(glyph_half_size_px - 6.0, -glyph_half_size_px - 6.0);
+        renderer.draw_text(status.icon(), icon_pos, STATUS_TEXT_SIZE, palette.hurt.with_alpha(alpha))?;
+    }
+
+    return Ok(());
+}
+
+// draw functions
+/// Drawing for the map, entities and text. The window draws through quicksilver,
+/// and RecordingRenderer keeps the calls so drawing can be checked without a window.
+trait Renderer {
+    /// A tileset glyph on a 16 pixel square, scaled up around its center
+    fn draw_glyph(&mut self, pos: Vector, glyph: char, color: Color) -> Result<()>;
+
+    /// A frame of a sprite animation, placed like a glyph.
+    /// Returns false if there is no such frame to draw.
+    fn draw_sprite(&mut self, pos: Vector, frame: SpriteFrame, color: Color) -> Result<bool>;
+
+    fn draw_rect(&mut self, rect: Rectangle, color: Color);
+
+    fn draw_line(&mut self, start: Vector, end: Vector, thickness: f32, color: Color);
+
+    fn draw_circle(&mut self, center: Vector, radius: f32, color: Color);
+
+    fn draw_text(&mut self, text: &str, pos: Vector, size: f32, color: Color) -> Result<()>;
+
+    /// The space that text takes up when drawn
+    fn text_size(&mut self, text: &str, size: f32) -> Result<Vector>;
+}
+
+/// Draws to the window with the tileset, sprite animations and font.
+/// Pre-rendered images still go to the window directly.
+// text, size and color, with the floats kept as bits so they can be hashed
+type TextKey = (String, u32, [u32; 4]);
+
+/// Rendered text, kept between frames so text is only rendered again when it changes
+struct TextCache {
+    images: HashMap<TextKey, Image>,
+    // the keys drawn since the last sweep
+    used: HashSet<TextKey>,
+}
+
+impl TextCache {
+    fn new() -> TextCache {
+        return TextCache {
+            images: HashMap::new(),
+            used: HashSet::new(),
+        };
+    }
+
+    fn image(&mut self, font: &Font, text: &str, size: f32, color: Color) -> Result<&Image> {
+        let key = (text.to_string(), size.to_bits(), [color.r.to_bits(), color.g.to_bits(), color.b.to_bits(), color.a.to_bits()]);
+        if !self.images.contains_key(&key) {
+            let image = font.render(text, &FontStyle::new(size, color))?;
+            self.images.insert(key.clone(), image);
+        }
+        self.used.insert(key.clone());
+
+        return Ok(&self.images[&key]);
+    }
+
+    /// Drop the text that was not drawn since the last sweep. Called once a frame,
+    /// so text that changes every frame does not pile up.
+    fn sweep(&mut self) {
+        let used = &self.used;
+        self.images.retain(|key, _image| used.contains(key));
+        self.used.clear();
+    }
+}
+
+struct WindowRenderer<'a> {
+    window: &'a mut Window,
+    char_map: &'a mut Asset<HashMap<u32, Image>>,
+    animation_library: &'a mut Asset<AnimationLibrary>,
+    font: &'a mut Asset<Font>,
+    text_cache: &'a mut TextCache,
+}
+
+impl<'a> WindowRenderer<'a> {
+    fn new(window: &'a mut Window,
+           char_map: &'a mut Asset<HashMap<u32, Image>>,
+           animation_library: &'a mut Asset<AnimationLibrary>,
+           font: &'a mut Asset<Font>,
+           text_cache: &'a mut TextCache) -> WindowRenderer<'a> {
+        return WindowRenderer {
+            window: window,
+            char_map: char_map,
+            animation_library: animation_library,
+            font: font,
+            text_cache: text_cache,
+        };
+    }
+}
 
-        let player_start = Vector::new(5, 3);
-        let mut entities = Vec::new();
-        entities.push(Entity {
-            last_pos: player_start,
-            pos: Vector::new(5, 3),
-            glyph: PLAYER_CHARACTER,
-            color: WHITE,
-            typ: EntityType::Player(Player { 
-                hp: 5,
-                max_hp: 5,
-                status: None,
-            }),
-            anim_state: AnimState::Idle(0),
+impl<'a> Renderer for WindowRenderer<'a> {
+    fn draw_glyph(&mut self, pos: Vector, glyph: char, color: Color) -> Result<()> {
+        let window = &mut self.window;
+        return self.char_map.execute(|char_map| {
+            draw_tile_image(window, pos, &char_map[&(glyph as u32)], color);
+            return Ok(());
         });
-        map[player_start.y as usize + player_start.x as usize * MAP_HEIGHT] =
-            Tile::wall(player_start.x as usize, player_start.y as usize);
-        let player_pos = generate_entities(false, &mut entities, &map);
-        entities[0].pos = player_pos;
+    }
 
-        for tile in map.iter_mut() {
-            if occupied_tile(tile.pos, &entities).is_some() {
-                *tile = Tile::wall(tile.pos.x as usize, tile.pos.y as usize);
+    fn draw_sprite(&mut self, pos: Vector, frame: SpriteFrame, color: Color) -> Result<bool> {
+        let window = &mut self.window;
+        let mut drawn = false;
+        self.animation_library.execute(|library| {
+            let image = library.get(frame.kind, frame.action).and_then(|sprite_anim| sprite_anim.frames.get(frame.index));
+            if let Some(image) = image {
+                draw_tile_image(window, pos, image, color);
+                drawn = true;
             }
-        }
+            return Ok(());
+        })?;
 
-        // The Square font: http://strlen.com/square/?s[]=font
-        // License: CC BY 3.0 https://creativecommons.org/licenses/by/3.0/deed.en_US
-        let font_square = "square.ttf";
-        let game_glyphs = "#@g.%";
-        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-        let tileset = Asset::new(Font::load(font_square).and_then(move |text| {
-            let tiles = text
-                .render(game_glyphs, &FontStyle::new(tile_size_px.y, WHITE))
-                .expect("Could not render the font tileset.");
-            let mut tileset = HashMap::new();
-            for (index, glyph) in game_glyphs.chars().enumerate() {
-                let pos = (index as i32 * tile_size_px.x as i32, 0);
-                let tile = tiles.subimage(Rectangle::new(pos, tile_size_px));
-                tileset.insert(glyph, tile);
-            }
-            Ok(tileset)
-        }));
+        return Ok(drawn);
+    }
 
-        Ok(Self {
-            game_state: GameState::Playing(0),
-            title,
-            mononoki_font_info,
-            square_font_info,
-            lost_game_message,
-            win_game_message,
-            char_map,
-            inventory,
-            map_size,
-            map,
-            entities,
-            player_id,
-            tileset,
-            noise: Perlin::new(),
-            gol_idle,
-            rook_idle,
-            player_idle,
-            time_passed: 0.0,
-            animations: Vec::new(),
-            gol_attack_right,
-            gol_attack_up,
-            gol_attack_down,
-            gol_attack_left,
-            rook_attack_up,
-            rook_attack_down,
-            rook_attack_right,
-            rook_attack_left,
-            gol_death,
-            rook_death,
-            trap_win,
-            trap_damage,
-            trap_arrow_up,
-            trap_arrow_down,
-            trap_arrow_right,
-            trap_arrow_left,
-            trap_random_direction,
-            trap_tele,
-        })
+    fn draw_rect(&mut self, rect: Rectangle, color: Color) {
+        self.window.draw(&rect, Col(color));
     }
 
-    /// Process keyboard and mouse, update the game state
-    fn update(&mut self, window: &mut Window) -> Result<()> {
+    fn draw_line(&mut self, start: Vector, end: Vector, thickness: f32, color: Color) {
+        self.window.draw(&Line::new(start, end).with_thickness(thickness), Col(color));
+    }
 
-        match self.game_state {
-            GameState::Win => {
-                let stairs_pos = self.entities.iter().find(|ent| {
-                    match ent.typ {
-                        EntityType::Trap(Trap::Win) => true,
-                        _ => false,
-                    }
-                }).unwrap().pos;
-                self.entities[0].pos = stairs_pos;
-            }
+    fn draw_circle(&mut self, center: Vector, radius: f32, color: Color) {
+        self.window.draw(&Circle::new(center, radius), Col(color));
+    }
 
-            GameState::NextLevel(n) => {
-                if n >= NUM_LEVEL_GAME {
-                    self.game_state = GameState::Win;
-                } else {
-                    let map_size = Vector::new(MAP_WIDTH as u8, MAP_HEIGHT as u8);
-                    let mut map = generate_map(map_size);
-                    self.map = generate_map(map_size);
+    fn draw_text(&mut self, text: &str, pos: Vector, size: f32, color: Color) -> Result<()> {
+        let window = &mut self.window;
+        let text_cache = &mut self.text_cache;
+        return self.font.execute(|font| {
+            let image = text_cache.image(font, text, size, color)?;
+            window.draw(&image.area().translate(pos), Img(image));
+            return Ok(());
+        });
+    }
+
+    fn text_size(&mut self, text: &str, size: f32) -> Result<Vector> {
+        let mut text_size = Vector::new(0, 0);
+        let text_cache = &mut self.text_cache;
+        self.font.execute(|font| {
+            text_size = text_cache.image(font, text, size, WHITE)?.area().size;
+            return Ok(());
+        })?;
 
-                    let player = self.entities[0].clone();
-                    self.entities.clear();
-                    self.entities.push(player);
-                    let player_pos = generate_entities(n + 1 == NUM_LEVEL_GAME, &mut self.entities, &self.map);
-                    self.entities[0].pos = player_pos;
+        return Ok(text_size);
+    }
+}
 
-                    self.game_state = GameState::Playing(n + 1);
-                }
-            }
+/// A draw call as kept by RecordingRenderer
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+enum DrawCall {
+    Glyph { pos: Vector, glyph: char, color: Color },
+    Sprite { pos: Vector, frame: SpriteFrame, color: Color },
+    Rect { rect: Rectangle, color: Color },
+    Line { start: Vector, end: Vector, thickness: f32, color: Color },
+    Circle { center: Vector, radius: f32, color: Color },
+    Text { text: String, pos: Vector, size: f32, color: Color },
+}
 
-            GameState::Playing(n) => {
-                let took_turn = update_player(self, window);
+/// Keeps every draw call in memory instead of drawing, so the render tests
+/// can check what is drawn without a window
+#[cfg(test)]
+#[derive(Clone, Debug, Default)]
+struct RecordingRenderer {
+    calls: Vec<DrawCall>,
+}
 
-                self.time_passed += MILLIS_PER_UPDATE / 1000.0;
-                if took_turn {
-                    self.time_passed = 0.0;
+#[cfg(test)]
+impl Renderer for RecordingRenderer {
+    fn draw_glyph(&mut self, pos: Vector, glyph: char, color: Color) -> Result<()> {
+        self.calls.push(DrawCall::Glyph { pos: pos, glyph: glyph, color: color });
+        return Ok(());
+    }
 
-                    let mut win = false;
-                    let mut next_level = false;
+    fn draw_sprite(&mut self, pos: Vector, frame: SpriteFrame, color: Color) -> Result<bool> {
+        self.calls.push(DrawCall::Sprite { pos: pos, frame: frame, color: color });
+        return Ok(true);
+    }
 
-                    update_monsters(self, window);
+    fn draw_rect(&mut self, rect: Rectangle, color: Color) {
+        self.calls.push(DrawCall::Rect { rect: rect, color: color });
+    }
 
-                    resolve_traps(&mut self.entities, &self.map, &mut self.animations, &mut next_level, &mut win);
+    fn draw_line(&mut self, start: Vector, end: Vector, thickness: f32, color: Color) {
+        self.calls.push(DrawCall::Line { start: start, end: end, thickness: thickness, color: color });
+    }
 
-                    if next_level {
-                        self.game_state = GameState::NextLevel(n);
-                    } else if win {
-                        self.game_state = GameState::Win;
-                    }
-                }
+    fn draw_circle(&mut self, center: Vector, radius: f32, color: Color) {
+        self.calls.push(DrawCall::Circle { center: center, radius: radius, color: color });
+    }
 
-                if window.keyboard()[Key::Escape].is_down() {
-                    window.close();
-                }
+    fn draw_text(&mut self, text: &str, pos: Vector, size: f32, color: Color) -> Result<()> {
+        self.calls.push(DrawCall::Text { text: text.to_string(), pos: pos, size: size, color: color });
+        return Ok(());
+    }
 
-                if self.entities[self.player_id].hp() <= 0 {
-                    self.game_state = GameState::Lost;
-                }
+    /// Without a font, each character is taken to be a little over half as wide as it is tall
+    fn text_size(&mut self, text: &str, size: f32) -> Result<Vector> {
+        return Ok(Vector::new(text.chars().count() as f32 * size * 0.6, size));
+    }
+}
 
-                self.entities = self.entities.iter().filter(|entity| {
-                    if entity.typ.is_monster() {
-                        return entity.hp() > 0;
-                    }
+/// Text on a dark box, kept inside the window
+fn draw_tooltip<R: Renderer>(text: &str, pos: Vector, renderer: &mut R, palette: &Palette) -> Result<()> {
+    let padding_px = Vector::new(4, 4);
+    let size = renderer.text_size(text, 14.0)? + padding_px * 2.0;
+    let pos = Vector::new(pos.x.min(WINDOW_WIDTH as f32 - size.x),
+                          pos.y.min(WINDOW_HEIGHT as f32 - size.y));
+    renderer.draw_rect(Rectangle::new(pos, size), palette.background.with_alpha(0.85));
+    return renderer.draw_text(text, pos + padding_px, 14.0, palette.text);
+}
 
-                    return true;
-                }).map(|ent| ent.clone()).collect();
-            },
+/// Glyphs and sprite frames are 16 pixel images, scaled up around their center
+fn draw_tile_image(window: &mut Window, pos: Vector, image: &Image, color: Color) {
+    let rect = Rectangle::new(pos, Vector::new(16, 16));
+    window.draw_ex(&rect,
+                   Blended(image, color),
+                   Transform::scale(Vector::new(SCALE, SCALE)),
+                   SCALE);
+}
 
-            GameState::Lost => {
-            },
+fn main() {
+    // play in the terminal instead of a window, for playing over SSH
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if std::env::args().any(|arg| arg == "--terminal") {
+            if let Err(err) = terminal::run() {
+                eprintln!("Terminal error: {}", err);
+            }
+            return;
         }
+    }
 
-        Ok(())
+    // NOTE: Set HIDPI to 1.0 to get pixel-perfect rendering.
+    // Otherwise the window resizes to whatever value the OS sets and
+    // scales the contents.
+    // https://docs.rs/glutin/0.19.0/glutin/dpi/index.html
+    std::env::set_var("WINIT_HIDPI_FACTOR", "1.0");
+
+    let config = load_config();
+    let settings = Settings {
+        // If the graphics do need to be scaled (e.g. using
+        // `with_center`), blur them. This looks better with fonts.
+        scale: quicksilver::graphics::ImageScaleStrategy::Blur,
+        // the window's viewport always covers the whole window, and Layout
+        // keeps the game in proportion within it
+        resize: ResizeStrategy::Stretch,
+        fullscreen: config.fullscreen,
+        min_size: Some(Vector::new(WINDOW_WIDTH / 2, WINDOW_HEIGHT / 2)),
+        draw_rate: DRAW_RATE_MS,
+        update_rate: MILLIS_PER_UPDATE,
+        ..Default::default()
+    };
+    run::<Game>("Stone Fall", Vector::new(WINDOW_WIDTH, WINDOW_HEIGHT), settings);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_from_unix_secs_gives_civil_date() {
+        assert_eq!(date_from_unix_secs(0), "1970-01-01");
+        assert_eq!(date_from_unix_secs(951782400), "2000-02-29");
+        assert_eq!(date_from_unix_secs(1709251199), "2024-02-29");
+        assert_eq!(date_from_unix_secs(1709251200), "2024-03-01");
     }
 
-    /// Draw stuff on the screen
-    fn draw(&mut self, window: &mut Window) -> Result<()> {
-        window.clear(BACKGROUND_COLOR)?;
+    #[test]
+    fn current_date_is_formatted() {
+        let date = current_date();
+        assert_eq!(date.len(), 10);
+        assert_eq!(date.chars().filter(|chr| *chr == '-').count(), 2);
+    }
 
-        // Draw the game title
-        self.title.execute(|image| {
-            window.draw(
-                &image
-                    .area()
-                    .with_center((window.screen_size().x as i32 / 2, 40)),
-                Img(&image),
-            );
-            Ok(())
-        })?;
+    #[test]
+    fn run_stats_count_turns_per_level() {
+        let mut stats = RunStats::new();
+        stats.take_turn();
+        stats.take_turn();
+        stats.start_level();
+        stats.take_turn();
 
-        // Draw the mononoki font credits
-        self.mononoki_font_info.execute(|image| {
-            window.draw(
-                &image
-                    .area()
-                    .translate((2, window.screen_size().y as i32 - 60)),
-                Img(&image),
-            );
-            Ok(())
-        })?;
+        assert_eq!(stats.turns, 3);
+        assert_eq!(stats.level_turns, vec![2, 1]);
+    }
 
-        // Draw the Square font credits
-        self.square_font_info.execute(|image| {
-            window.draw(
-                &image
-                    .area()
-                    .translate((2, window.screen_size().y as i32 - 30)),
-                Img(&image),
-            );
-            Ok(())
-        })?;
+    #[test]
+    fn run_stats_total_damage_and_traps_by_name() {
+        let mut stats = RunStats::new();
+        stats.damage("Gol", 1);
+        stats.damage("Gol", 2);
+        stats.damage("Kill", 5);
+        stats.trap_triggered(Trap::Kill);
+        stats.trap_triggered(Trap::Kill);
+
+        assert_eq!(stats.damage_taken.get("Gol"), Some(&3));
+        assert_eq!(stats.damage_taken.get("Kill"), Some(&5));
+        assert_eq!(stats.traps_triggered.get("Kill"), Some(&2));
+    }
 
-        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-        let offset_px = Vector::new(MAP_DRAW_X_OFFSET as u8, MAP_DRAW_Y_OFFSET as u8);
+    #[test]
+    fn run_stats_kill_records_level() {
+        let mut stats = RunStats::new();
+        stats.start_level();
+        stats.kill(MonsterType::Gol, "Kill");
 
-        // draw map
-        for tile in self.map.iter() {
-            draw_tile(tile, window, offset_px, &mut self.char_map, &mut self.noise);
+        assert_eq!(stats.kills.len(), 1);
+        assert_eq!(stats.kills[0].level, 2);
+        assert_eq!(stats.kills[0].monster, "Gol");
+        assert_eq!(stats.kills[0].cause, "Kill");
+    }
+
+    #[test]
+    fn run_stats_score() {
+        let mut stats = RunStats::new();
+        stats.levels_cleared = 2;
+        stats.trap_kills = 1;
+        stats.combat_kills = 1;
+        for _ in 0..10 {
+            stats.take_turn();
         }
 
-        // draw entities
-        // draw traps
-        for entity in self.entities.iter() {
-            if entity.typ.is_trap() && entity.anim_state.is_none() {
-                let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-                let pos_px = entity.pos.times(tile_size_px);
-                let pos = offset_px + pos_px;
-                draw_entity(entity, pos, window, &mut self.char_map);
-            }
+        assert_eq!(stats.score(3, 1),
+                   2 * SCORE_PER_LEVEL + 3 * SCORE_PER_HP + SCORE_PER_TRAP_KILL + SCORE_PER_COMBAT_KILL +
+                   10 * SCORE_PER_TURN + SCORE_PER_UNDO);
+    }
+
+    #[test]
+    fn run_stats_score_is_never_negative() {
+        let mut stats = RunStats::new();
+        for _ in 0..10 {
+            stats.take_turn();
         }
 
-        // draw other entities
-        for entity in self.entities.iter_mut() {
-            let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-            let ent_pos = entity.pos;
-            let last_ent_pos = entity.last_pos;
-            let lerp_amount = clamp(0.0, ITERP_TIME as f32, self.time_passed as f32) / ITERP_TIME as f32;
-            let ent_pos = Vector::new(lerp(last_ent_pos.x, ent_pos.x, lerp_amount),
-                                      lerp(last_ent_pos.y, ent_pos.y, lerp_amount));
-            if magnitude(ent_pos - entity.pos) < 0.01 {
-                entity.last_pos = entity.pos;
-            }
-            let pos_px = ent_pos.times(tile_size_px);
-            let pos = offset_px + pos_px;
+        assert_eq!(stats.score(-2, 10), 0);
+    }
 
-            match entity.anim_state {
-                AnimState::None => {
-                    draw_entity(entity, pos, window, &mut self.char_map);
-                }
+    #[test]
+    fn color_from_hex_reads_channels() {
+        let color = color_from_hex("#ff8000").unwrap();
+        assert_eq!((color.r, color.g, color.b, color.a), (1.0, 128.0 / 255.0, 0.0, 1.0));
 
-                AnimState::Attacking(index, direction) => {
-                    match entity.typ {
-                        EntityType::Monster(_) => {
-                            let anims;
-                            if entity.typ.is_rook() {
-                                anims = &mut self.rook_attack_right;
-                            } else {
-                                match direction {
-                                    Arrow::Up => anims = &mut self.gol_attack_up,
-                                    Arrow::Down => anims = &mut self.gol_attack_down,
-                                    Arrow::Right => anims = &mut self.gol_attack_right,
-                                    Arrow::Left => anims = &mut self.gol_attack_left,
-                                }
-                            }
-                            anims.execute(|anims| {
-                                let rect = Rectangle::new(pos,
-                                                          Vector::new(16, 16));
-                                let anim_index = index / DRAWS_PER_ATTACK_FRAME;
-                                window.draw_ex(&rect,
-                                               Blended(&anims[anim_index], entity.color),
-                                               Transform::scale(Vector::new(SCALE, SCALE)),
-                                               SCALE);
-                                if (index + 1) >= (anims.len() * DRAWS_PER_ATTACK_FRAME) {
-                                    entity.anim_state = AnimState::Idle(0);
-                                } else {
-                                    entity.anim_state = AnimState::Attacking(index + 1, direction);
-                                }
-                                return Ok(());
-                            }).unwrap();
-                        },
+        assert_eq!(color_from_hex("ff8000"), Some(color));
+        assert_eq!(color_from_hex("#ff80"), None);
+        assert_eq!(color_from_hex("#gg8000"), None);
+    }
 
-                        _ => continue,
-                    }
-                }
+    fn palette_file(colors: &[(&str, &str)]) -> PaletteFile {
+        return PaletteFile {
+            name: "Test".to_string(),
+            outlines: true,
+            colors: colors.iter().map(|(name, hex)| (name.to_string(), hex.to_string())).collect(),
+        };
+    }
 
-                AnimState::Idle(index) => {
-                    match entity.typ {
-                        EntityType::Monster(_) | EntityType::Player(_) | EntityType::Trap(_) => {
-                            let idle_anims;
-                            if entity.typ.is_player() {
-                                idle_anims = &mut self.player_idle;
-                            } else if entity.typ.is_monster() {
-                                if entity.typ.is_rook() {
-                                idle_anims = &mut self.rook_idle;
-                                } else {
-                                    idle_anims = &mut self.gol_idle;
-                                }
-                            } else if entity.typ.is_trap() {
-                                match entity.typ {
-                                    EntityType::Trap(trap) => {
-                                        match trap {
-                                            Trap::Kill => idle_anims = &mut self.trap_damage,
-                                            Trap::Bump => idle_anims = &mut self.trap_random_direction,
-                                            Trap::Teleport => idle_anims = &mut self.trap_tele,
-                                            Trap::Win => idle_anims = &mut self.trap_win,
-                                            Trap::Arrow(arrow) => {
-                                                match arrow {
-                                                    Arrow::Up => idle_anims = &mut self.trap_arrow_up,
-                                                    Arrow::Down => idle_anims = &mut self.trap_arrow_down,
-                                                    Arrow::Right => idle_anims = &mut self.trap_arrow_right,
-                                                    Arrow::Left => idle_anims = &mut self.trap_arrow_left,
-                                                }
-                                            },
-                                            
-                                            _ => panic!("Should not be here..."),
-                                        }
-                                    },
-
-                                    _ => panic!("Unreachable type!"),
-                                }
-                            } else {
-                                panic!("Unreachable arm for animation!");
-                            }
+    #[test]
+    fn palette_from_file_overrides_defaults() {
+        let palette = Palette::from_file(palette_file(&[("monster", "#0000ff")])).unwrap();
 
-                            idle_anims.execute(|idle_anims| {
-                                let rect = Rectangle::new(pos,
-                                                          Vector::new(16, 16));
-                                let anim_index = index / DRAWS_PER_IDLE_FRAME;
-                                window.draw_ex(&rect,
-                                               Blended(&idle_anims[anim_index], entity.color),
-                                               Transform::scale(Vector::new(SCALE, SCALE)),
-                                               SCALE);
-                                if (index + 1) >= (idle_anims.len() * DRAWS_PER_IDLE_FRAME) {
-                                    entity.anim_state = AnimState::Idle(0);
-                                } else {
-                                    entity.anim_state = AnimState::Idle(index + 1);
-                                }
-                                return Ok(());
-                            }).unwrap();
-                        },
-                    }
-                }
-            }
-        }
+        assert_eq!(palette.name, "Test");
+        assert!(palette.outlines);
+        assert_eq!(palette.monster, Color { r: 0.0, g: 0.0, b: 1.0, a: 1.0 });
+        assert_eq!(palette.player, Palette::default().player);
+    }
 
-        // draw animations
-        let mut animations_done = Vec::new();
-        for (animation_index, animation) in self.animations.iter_mut().enumerate() {
-            match animation {
-                Animation::MonsterDeath(monster_typ, loc, sprite_index) => {
-                    let anims;
-                    match monster_typ {
-                        MonsterType::Gol => {
-                            anims = &mut self.gol_death;
-                        },
+    #[test]
+    fn palette_from_file_rejects_bad_entries() {
+        assert!(Palette::from_file(palette_file(&[("monster", "blue")])).is_err());
+        assert!(Palette::from_file(palette_file(&[("monsters", "#0000ff")])).is_err());
+    }
 
-                        MonsterType::Rook => {
-                            anims = &mut self.rook_death;
-                        },
-                    }
+    #[test]
+    fn message_log_scrolls_within_its_lines() {
+        let mut log = MessageLog::new();
+        for index in 0..LOG_VISIBLE_LINES + 3 {
+            log.add(format!("message {}", index));
+        }
 
-                    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-                    let pos_px = loc.times(tile_size_px);
-                    let pos = offset_px + pos_px;
-
-                    let mut anim_len = 0;
-                    anims.execute(|anims| {
-                        let rect = Rectangle::new(pos,
-                                                  Vector::new(16, 16));
-                        let anim_index = *sprite_index / DRAWS_PER_DEATH_FRAME;
-                        window.draw_ex(&rect,
-                                       Blended(&anims[anim_index], MONSTER_COLOR),
-                                       Transform::scale(Vector::new(SCALE, SCALE)),
-                                       SCALE);
-                        anim_len = anims.len();
-                        return Ok(());
-                    }).unwrap();
+        let visible = log.visible_lines();
+        assert_eq!(visible.len(), LOG_VISIBLE_LINES);
+        assert_eq!(visible[0], "message 3");
+        assert_eq!(visible[LOG_VISIBLE_LINES - 1], format!("message {}", LOG_VISIBLE_LINES + 2));
 
-                    if (*sprite_index + 1) >= (anim_len * DRAWS_PER_DEATH_FRAME) {
-                        animations_done.push(animation_index);
-                    } else {
-                        *animation = Animation::MonsterDeath(monster_typ.clone(), *loc, *sprite_index + 1);
-                    }
-                }
+        log.scroll_by(2);
+        assert_eq!(log.visible_lines()[0], "message 1");
 
-                Animation::MonsterAttack(monster_typ, loc, sprite_index) => {
-                    let tile = self.map.iter().find(|other_tile| other_tile.pos == *loc).unwrap(); //[loc.y as usize + loc.x as usize * MAP_HEIGHT];
-                    draw_tile(tile, window, offset_px, &mut self.char_map, &mut self.noise);
-                    //let sprites;
-                    match monster_typ {
-                        MonsterType::Gol => {
-                            //sprites = game.gol_attack;
-                        }
+        log.scroll_by(100);
+        assert_eq!(log.scroll, 3);
+        assert_eq!(log.visible_lines()[0], "message 0");
 
-                        MonsterType::Rook => {
-                            //sprites = game.rook_attack;
-                        }
-                    }
-                }
-            }
+        log.scroll_by(-100);
+        assert_eq!(log.scroll, 0);
+    }
+
+    #[test]
+    fn message_log_keeps_place_when_scrolled_back() {
+        let mut log = MessageLog::new();
+        for index in 0..LOG_VISIBLE_LINES + 3 {
+            log.add(format!("message {}", index));
         }
 
-        // TODO remove used animation
+        log.scroll_by(1);
+        let before = log.visible_lines();
+        log.add("a new message".to_string());
 
-        let player = &self.entities[self.player_id];
-        let full_health_width_px = 100.0;
-        let current_health_width_px =
-            (player.hp() as f32 / player.max_hp() as f32) * full_health_width_px;
+        assert_eq!(log.visible_lines(), before);
+    }
 
-        let map_size_px = self.map_size.times(tile_size_px);
-        let health_bar_pos_px = offset_px + Vector::new(map_size_px.x, 0.0);
+    /// A trap the player has already found
+    fn found_trap(pos: Vector, trap: Trap) -> Entity {
+        let mut entity = Entity::trap(pos, trap);
+        entity.hidden = false;
+        return entity;
+    }
 
-        // Full health
-        window.draw(
-            &Rectangle::new(health_bar_pos_px, (full_health_width_px, tile_size_px.y)),
-            Col(Color::RED.with_alpha(0.5)),
-        );
+    #[test]
+    fn preview_move_stops_at_walls() {
+        let entities = vec![Entity::player(Vector::new(1, 1))];
+        let map = vec![Tile { pos: Vector::new(2, 1), glyph: WALL_CHAR, color: WHITE, blocks: true }];
 
-        // Current health
-        window.draw(
-            &Rectangle::new(health_bar_pos_px, (current_health_width_px, tile_size_px.y)),
-            Col(Color::RED),
-        );
+        assert_eq!(preview_move(&entities, &map, 0, Direction::Right), None);
+    }
 
-        // Current health
-        self.inventory.execute(|image| {
-            window.draw(
-                &image
-                    .area()
-                    .translate(health_bar_pos_px + Vector::new(0, tile_size_px.y)),
-                Img(&image),
-            );
-            Ok(())
-        })?;
+    #[test]
+    fn preview_move_shows_visible_trap_damage() {
+        let entities = vec![Entity::player(Vector::new(1, 1)), found_trap(Vector::new(2, 1), Trap::Kill)];
 
-        // Draw Message
-        if self.game_state == GameState::Lost {
-            self.lost_game_message.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .translate((MAP_DRAW_X_OFFSET as u16 + 40, WINDOW_HEIGHT as u16 - 100)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-        }
+        let preview = preview_move(&entities, &Vec::new(), 0, Direction::Right).unwrap();
 
-        if self.game_state == GameState::Win {
-            self.win_game_message.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .translate((MAP_DRAW_X_OFFSET as u16 + 40, WINDOW_HEIGHT as u16 - 100)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-        }
+        assert_eq!(preview.landing, Vector::new(2, 1));
+        assert_eq!(preview.traps, vec![(Vector::new(2, 1), false)]);
+        assert_eq!(preview.damage, 5);
+        assert!(preview.dies);
+    }
+
+    #[test]
+    fn preview_move_leaves_out_hidden_traps() {
+        let mut trap = Entity::trap(Vector::new(2, 1), Trap::Kill);
+        trap.hidden = true;
+        let entities = vec![Entity::player(Vector::new(1, 1)), trap];
+
+        let preview = preview_move(&entities, &Vec::new(), 0, Direction::Right).unwrap();
 
-        //let mut rng = thread_rng();
-        //for entity in self.entities.iter_mut() {
-        //    if (entity.typ.is_monster() || entity.typ.is_player()) &&
-        //        entity.idle == None &&
-        //        rng.gen_range(0.0, 1.0) < IDLE_PROB {
+        assert!(preview.traps.is_empty());
+        assert_eq!(preview.damage, 0);
+        assert!(!preview.dies);
+    }
 
-        //        entity.idle = AnimState::Idle(0);
-        //    }
-        //}
+    #[test]
+    fn preview_move_lists_bump_landings() {
+        let entities = vec![Entity::player(Vector::new(3, 3)), found_trap(Vector::new(4, 3), Trap::Bump)];
+        let map = vec![Tile { pos: Vector::new(5, 3), glyph: WALL_CHAR, color: WHITE, blocks: true }];
 
-        Ok(())
+        let preview = preview_move(&entities, &map, 0, Direction::Right).unwrap();
+
+        assert_eq!(preview.landing, Vector::new(4, 3));
+        assert_eq!(preview.bump_landings.len(), 8);
+        assert!(preview.bump_landings.contains(&Vector::new(4, 3)));
+        assert!(!preview.bump_landings.contains(&Vector::new(5, 3)));
     }
-}
 
-fn direction(dir: Vector) -> Arrow {
-    if dir.x > 0.0 && dir.y == 0.0 {
-        Arrow::Right
-    } else if dir.x == 0.0 && dir.y < 0.0 {
-        Arrow::Up
-    } else if dir.x < 0.0 {
-        Arrow::Left
-    } else { // if dir.y < 0 {
-        Arrow::Down
+    #[test]
+    fn draw_tile_places_glyph_on_its_tile() {
+        let mut renderer = RecordingRenderer::default();
+        let palette = Palette::default();
+        let tile = Tile { pos: Vector::new(2, 3), glyph: WALL_CHAR, color: WHITE, blocks: true };
+
+        draw_tile(&tile, &mut renderer, Vector::new(10, 20), &Perlin::new(), &palette).unwrap();
+
+        let pos = Vector::new(10.0 + 2.0 * TILE_WIDTH_PX as f32, 20.0 + 3.0 * TILE_HEIGHT_PX as f32);
+        assert_eq!(renderer.calls, vec![DrawCall::Glyph { pos: pos, glyph: WALL_CHAR, color: palette.wall }]);
     }
-}
 
-// Update Functions
-fn update_monsters(game: &mut Game, _window: &mut Window) {
-    let player = game.entities[game.player_id].clone();
-    // NOTE copies all entities every frame!
-    let entities = game.entities.clone();
-
-    let mut attacks: Vec<(EntityId, EntityId)> = Vec::new();
-
-    // For each monster
-    for (index, monster) in game.entities.iter_mut().enumerate().filter(|(_index, entity)| entity.typ.is_monster()) {
-        let prev_position = monster.pos;
-
-        let pos_diff = player.pos - monster.pos;
-        let mut pos_move = monster.pos;
-        pos_move.x += pos_diff.x.abs().signum() * pos_diff.x.signum();
-        pos_move.y += pos_diff.y.abs().signum() * pos_diff.y.signum();
-        // attempt to constrain rooks to lane movement.
-        if monster.typ.is_rook() && pos_move.x.abs() == pos_move.y.abs() {
-            if pos_diff.x.abs() > pos_diff.y.abs() && !blocked_tile(pos_move, &game.map) {
-                pos_move.y = 0.0;
-            } else {
-                pos_move.x = 0.0;
-            }
-        }
+    #[test]
+    fn draw_entity_uses_palette_color() {
+        let mut renderer = RecordingRenderer::default();
+        let palette = Palette::default();
+        let gol = Entity::gol(Vector::new(1, 1));
 
-        if blocked_tile(pos_move, &game.map) {
-            pos_move = prev_position;
-        } else if let Some(entity) = occupied_tile(pos_move, &entities) {
-            if entity.typ.is_player() {
-                let dir = direction(pos_move - prev_position);
-                pos_move = prev_position;
-                //attacks.push((index, entities.iter().enumerate().find(|(_index, ent)| **ent == entity).unwrap().0));
-                attacks.push((index, game.player_id));
-                // TODO choose arrow based on attack direction...
-                monster.anim_state = AnimState::Attacking(0, dir);
-            }  else if entity.typ.is_monster() {
-                // TODO add for monsters too...
-                pos_move = prev_position;
-            }
-        }
+        draw_entity(&gol, Vector::new(50, 60), &mut renderer, &palette).unwrap();
 
-        monster.pos = pos_move;
+        assert_eq!(renderer.calls, vec![DrawCall::Glyph { pos: Vector::new(50, 60), glyph: GOL_CHAR, color: palette.monster }]);
     }
 
-    // resolve attacks that occured
-    for attack in attacks.iter() {
-        let typ = &mut game.entities[attack.1].typ;
-        match typ {
-            EntityType::Player(_player) => {
-                typ.lose_hp(1);
-            },
+    #[test]
+    fn draw_entity_skips_hidden_traps() {
+        let mut renderer = RecordingRenderer::default();
+        let mut trap = Entity::trap(Vector::new(1, 1), Trap::Kill);
+        trap.hidden = true;
 
-            EntityType::Monster(_monster) => {
-                typ.lose_hp(1);
-            },
+        draw_entity(&trap, Vector::new(50, 60), &mut renderer, &Palette::default()).unwrap();
 
-            _ => { },
-        }
+        assert!(renderer.calls.is_empty());
+    }
+
+    #[test]
+    fn draw_tooltip_draws_box_then_text() {
+        let mut renderer = RecordingRenderer::default();
+        let palette = Palette::default();
+
+        draw_tooltip("Gol", Vector::new(100, 100), &mut renderer, &palette).unwrap();
+
+        let size = Vector::new(3.0 * 14.0 * 0.6 + 8.0, 14.0 + 8.0);
+        assert_eq!(renderer.calls,
+                   vec![DrawCall::Rect { rect: Rectangle::new((100, 100), size), color: palette.background.with_alpha(0.85) },
+                        DrawCall::Text { text: "Gol".to_string(), pos: Vector::new(104, 104), size: 14.0, color: palette.text }]);
+    }
+
+    #[test]
+    fn draw_tooltip_stays_inside_the_layout() {
+        let mut renderer = RecordingRenderer::default();
 
-        // only monsters have attack animations
-        match game.entities[attack.0].typ {
-            EntityType::Monster(_monster) => {
-                let pos = game.entities[attack.0].pos;
-                //let anim = Animation::MonsterAttack(monster.typ.clone(), pos, 0);
-                // TODO removes this kind of animation for attacks...
-                //game.animations.push(anim);
+        draw_tooltip("Gol", Vector::new(WINDOW_WIDTH, WINDOW_HEIGHT), &mut renderer, &Palette::default()).unwrap();
+
+        match renderer.calls[0] {
+            DrawCall::Rect { rect, .. } => {
+                assert_eq!(rect.pos.x + rect.size.x, WINDOW_WIDTH as f32);
+                assert_eq!(rect.pos.y + rect.size.y, WINDOW_HEIGHT as f32);
             },
-            
-            _ => (),
+
+            ref call => panic!("expected the tooltip box first, drew {:?}", call),
         }
     }
 
-    let remove_indices: Vec<usize> =
-        game.entities.iter()
-                     .enumerate()
-                     .filter(|(_ix, ent)| ent.typ.is_monster() && ent.hp() <= 0)
-                     .map(|(ix, _ent)| ix)
-                     .collect();
-    for ix in remove_indices {
-        dbg!();
-        game.entities.swap_remove(ix);
+    /// The player steps right while a gol walks onto a kill trap
+    fn play_trap_turn() -> (Vec<Entity>, Vec<GameEvent>) {
+        let mut entities = vec![Entity::player(Vector::new(1, 1)),
+                                Entity::gol(Vector::new(4, 1)),
+                                Entity::trap(Vector::new(3, 2), Trap::Kill)];
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut events = Vec::new();
+        let mut next_level = false;
+        let mut win = false;
+
+        let took_turn = play_turn(&mut entities, &Vec::new(), &mut rng, 0, Action::Move(Direction::Right),
+                                  &mut events, &mut next_level, &mut win);
+
+        assert!(took_turn);
+        assert!(!next_level && !win);
+        return (entities, events);
     }
 
-    // check for idle animations
-    //let mut rng = thread_rng();
-    //for entity in game.entities.iter_mut() {
-    //    if entity.typ.is_monster() &&
-    //        entity.idle == None &&
-    //        rng.gen_range(0.0, 1.0) < IDLE_PROB {
+    #[test]
+    fn play_turn_reports_events_in_order() {
+        let (entities, events) = play_trap_turn();
+
+        assert_eq!(entities[0].pos, Vector::new(2, 1));
+        assert_eq!(events.len(), 5);
+        assert!(matches!(events[0], GameEvent::Moved { typ: EntityType::Player(_), from, to }
+                                    if from == Vector::new(1, 1) && to == Vector::new(2, 1)));
+        assert!(matches!(events[1], GameEvent::Moved { typ: EntityType::Monster(_), from, to }
+                                    if from == Vector::new(4, 1) && to == Vector::new(3, 2)));
+        assert!(matches!(events[2], GameEvent::TrapTriggered(TrapTrigger { trap: Trap::Kill, typ: EntityType::Monster(_), pos, .. })
+                                    if pos == Vector::new(3, 2)));
+        assert!(matches!(events[3], GameEvent::Damaged { typ: EntityType::Monster(_), amount: 5, cause: Cause::Trap(Trap::Kill), .. }));
+        assert!(matches!(events[4], GameEvent::Died { typ: EntityType::Monster(_), cause: Cause::Trap(Trap::Kill), .. }));
+    }
 
-    //        entity.idle = Some(0);
-    //    }
-    //}
-}
+    #[test]
+    fn play_turn_does_not_take_a_turn_into_a_wall() {
+        let mut entities = vec![Entity::player(Vector::new(1, 1))];
+        let map = vec![Tile { pos: Vector::new(2, 1), glyph: WALL_CHAR, color: WHITE, blocks: true }];
+        let mut events = Vec::new();
 
-fn lerp_color(src: Color, dst: Color, amount: f32) -> Color {
-    return Color {
-        r: lerp(src.r, dst.r, amount),
-        g: lerp(src.g, dst.g, amount),
-        b: lerp(src.b, dst.b, amount),
-        a: lerp(src.a, dst.a, amount),
-    };
-}
+        let took_turn = play_turn(&mut entities, &map, &mut StdRng::seed_from_u64(1), 0, Action::Move(Direction::Right),
+                                  &mut events, &mut false, &mut false);
 
-fn attempt_move(pos: Vector, offset: Vector, map: &Map) -> Vector {
-    let mut new_pos = pos + offset;
+        assert!(!took_turn);
+        assert_eq!(entities[0].pos, Vector::new(1, 1));
+        assert!(events.is_empty());
+    }
 
-    if blocked_tile(new_pos, map) {
-        new_pos = pos;
+    #[test]
+    fn play_turn_monster_attacks_adjacent_player() {
+        let mut entities = vec![Entity::player(Vector::new(2, 1)), Entity::gol(Vector::new(3, 2))];
+        let mut events = Vec::new();
+
+        play_turn(&mut entities, &Vec::new(), &mut StdRng::seed_from_u64(1), 0, Action::Wait,
+                  &mut events, &mut false, &mut false);
+
+        assert_eq!(entities[0].hp(), 4);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], GameEvent::Attacked { attacker: EntityType::Monster(_), target: EntityType::Player(_), .. }));
+        assert!(matches!(events[1], GameEvent::Damaged { typ: EntityType::Player(_), amount: 1, cause: Cause::Attack(EntityType::Monster(_)), .. }));
     }
 
-    return new_pos;
-}
+    #[test]
+    fn deal_damage_reports_death_once() {
+        let mut gol = Entity::gol(Vector::new(1, 1));
+        let mut events = Vec::new();
 
-fn update_player(game: &mut Game, window: &mut Window) -> bool {
-    use ButtonState::*;
+        deal_damage(&mut gol, 1, Cause::Trap(Trap::Kill), &mut events);
+        deal_damage(&mut gol, 1, Cause::Trap(Trap::Kill), &mut events);
 
-    let mut took_turn: bool = false;
+        assert_eq!(gol.hp(), -1);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0], GameEvent::Damaged { amount: 1, .. }));
+        assert!(matches!(events[1], GameEvent::Died { .. }));
+        assert!(matches!(events[2], GameEvent::Damaged { amount: 1, .. }));
+    }
 
-    let player = &mut game.entities[game.player_id];
-    let previous_pos = player.pos;
-    if window.keyboard()[Key::Left] == Pressed {
-        player.pos.x = clamp(0.0, MAP_WIDTH as f32, player.pos.x - 1.0);
-        took_turn = true;
+    #[test]
+    fn deal_damage_to_survivor_is_not_a_death() {
+        let mut player = Entity::player(Vector::new(1, 1));
+        let mut events = Vec::new();
+
+        deal_damage(&mut player, 2, Cause::Attack(Entity::gol(Vector::new(2, 1)).typ), &mut events);
+
+        assert_eq!(player.hp(), 3);
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], GameEvent::Damaged { typ: EntityType::Player(_), amount: 2, .. }));
     }
-    if window.keyboard()[Key::Right] == Pressed {
-        player.pos.x = clamp(0.0, MAP_WIDTH as f32, player.pos.x + 1.0);
-        took_turn = true;
+
+    #[test]
+    fn message_log_describes_turn() {
+        let (_entities, events) = play_trap_turn();
+        let mut log = MessageLog::new();
+        for event in events.iter() {
+            log.on_event(event);
+        }
+
+        assert_eq!(log.messages, vec!["The Gol steps on a kill trap".to_string(),
+                                      "The Gol is hurt (-5)".to_string(),
+                                      "The Gol dies".to_string()]);
     }
-    if window.keyboard()[Key::Up] == Pressed {
-        player.pos.y = clamp(0.0, MAP_HEIGHT as f32, player.pos.y - 1.0);
-        took_turn = true;
+
+    #[test]
+    fn message_log_describes_attacks_on_player() {
+        let mut log = MessageLog::new();
+        let gol = Entity::gol(Vector::new(2, 1));
+        let player = Entity::player(Vector::new(1, 1));
+
+        log.on_event(&GameEvent::Damaged { typ: player.typ, pos: player.pos, amount: 1, cause: Cause::Attack(gol.typ) });
+        log.on_event(&GameEvent::Died { typ: player.typ, pos: player.pos, cause: Cause::Attack(gol.typ) });
+        log.on_event(&GameEvent::LevelCleared(0));
+
+        assert_eq!(log.messages, vec!["The Gol attacks you (-1)".to_string(),
+                                      "You die".to_string(),
+                                      "You clear level 1".to_string()]);
     }
-    if window.keyboard()[Key::Down] == Pressed {
-        player.pos.y = clamp(0.0, MAP_HEIGHT as f32, player.pos.y + 1.0);
-        took_turn = true;
+
+    #[test]
+    fn run_stats_count_turn() {
+        let (_entities, events) = play_trap_turn();
+        let mut stats = RunStats::new();
+        for event in events.iter() {
+            stats.on_event(event);
+        }
+
+        assert_eq!(stats.trap_kills, 1);
+        assert_eq!(stats.combat_kills, 0);
+        assert_eq!(stats.traps_triggered.get("Kill"), Some(&1));
+        assert!(stats.damage_taken.is_empty());
+        assert_eq!(stats.kills.len(), 1);
+        assert_eq!(stats.kills[0].monster, "Gol");
+        assert_eq!(stats.kills[0].cause, "Kill");
     }
 
-    if blocked_tile(player.pos, &game.map) {
-        player.pos = previous_pos;
-        took_turn = false;
+    #[test]
+    fn run_stats_count_damage_to_player_by_source() {
+        let mut stats = RunStats::new();
+        let player = Entity::player(Vector::new(1, 1));
+        let gol = Entity::gol(Vector::new(2, 1));
+
+        stats.on_event(&GameEvent::Damaged { typ: player.typ, pos: player.pos, amount: 1, cause: Cause::Attack(gol.typ) });
+        stats.on_event(&GameEvent::Damaged { typ: player.typ, pos: player.pos, amount: 2, cause: Cause::Attack(gol.typ) });
+        stats.on_event(&GameEvent::LevelCleared(0));
+
+        assert_eq!(stats.damage_taken.get("Gol"), Some(&3));
+        assert_eq!(stats.levels_cleared, 1);
     }
 
-    return took_turn;
-}
+    /// How many times the monster at index 1 moves over a number of player turns
+    fn monster_moves(entities: &mut [Entity], turns: usize) -> Vec<usize> {
+        let mut moves = Vec::new();
+        for _ in 0..turns {
+            let mut events = Vec::new();
+            monster_turns(entities, &Vec::new(), 0, &mut events);
+            moves.push(events.iter().filter(|event| matches!(event, GameEvent::Moved { .. })).count());
+        }
+        return moves;
+    }
 
-fn resolve_traps(entities: &mut Vec<Entity>, map: &Map, animations: &mut Vec<Animation>, next_level: &mut bool, win: &mut bool) {
-    let mut rng = thread_rng();
-    let entities_clone = entities.clone();
-    let mut removals: Vec<usize> = Vec::new();
-    let mut moves: Vec<(Vector, usize)> = Vec::new();
-    let mut count_downs: Vec<(usize, u8)> = Vec::new();
+    #[test]
+    fn monster_turns_double_speed_acts_twice() {
+        let mut gol = Entity::gol(Vector::new(10, 10));
+        gol.speed = NORMAL_SPEED * 2;
+        let mut entities = vec![Entity::player(Vector::new(1, 1)), gol];
 
-    let trap_iter =
-        entities.iter_mut()
-                .enumerate()
-                .filter(|(_index, ent)| ent.typ.is_player() || ent.typ.is_monster());
-    for (index, entity) in trap_iter {
-        if let Some(trap_entity) = trap_tile(entity.pos, &entities_clone) {
-            let trap_index = entities_clone.iter().position(|other| *other == trap_entity).unwrap();
-            match trap_entity.typ {
-                EntityType::Trap(trap) => {
-                    match trap {
-                        Trap::Berserk => {
-                            match entity.typ {
-                                EntityType::Monster(mut monster) => {
-                                    // TODO try setting entity
-                                    monster.status = Some(Status::Berserk);
-                                },
+        assert_eq!(monster_moves(&mut entities, 2), vec![2, 2]);
+        assert_eq!(entities[1].pos, Vector::new(6, 6));
+        assert_eq!(entities[1].energy, 0);
+    }
 
-                                EntityType::Player(mut player) => {
-                                    // TODO try setting entity
-                                    player.status = Some(Status::Berserk);
-                                },
+    #[test]
+    fn monster_turns_half_speed_acts_every_other_turn() {
+        let mut gol = Entity::gol(Vector::new(10, 10));
+        gol.speed = NORMAL_SPEED / 2;
+        let mut entities = vec![Entity::player(Vector::new(1, 1)), gol];
 
-                                _ => panic!("Unexpected entity type!"),
-                            }
-                        },
+        assert_eq!(monster_moves(&mut entities, 4), vec![0, 1, 0, 1]);
+    }
 
-                        Trap::Kill => {
-                            entity.typ.lose_hp(5);
-                            removals.push(trap_index);
-                            match entity.typ {
-                                EntityType::Monster(monster) => {
-                                    animations.push(Animation::MonsterDeath(monster.typ,
-                                                                            entity.pos,
-                                                                            0));
-                                }
+    #[test]
+    fn monster_turns_berserk_doubles_speed() {
+        let mut gol = Entity::gol(Vector::new(10, 10));
+        if let EntityType::Monster(monster) = &mut gol.typ {
+            monster.status = Some(Status::Berserk);
+        }
+        let mut entities = vec![Entity::player(Vector::new(1, 1)), gol];
 
-                                _ => (),
-                            }
-                        },
+        assert_eq!(entities[1].speed(), NORMAL_SPEED * 2);
+        assert_eq!(monster_moves(&mut entities, 1), vec![2]);
+    }
 
-                        Trap::Teleport => {
-                            // find next teleport. if one is find, move character there.
-                            let entities_len = entities_clone.len();
-                            for other_index in 0..entities_len {
-                                let offset_index = (other_index + trap_index + 1) % entities_len;
-                                let other_entity = &entities_clone[offset_index];
-                                match other_entity.typ {
-                                    EntityType::Trap(Trap::Teleport) => {
-                                        entity.pos = other_entity.pos;
-                                        break;
-                                    },
+    #[test]
+    fn monster_turns_dead_monsters_gain_no_energy() {
+        let mut gol = Entity::gol(Vector::new(10, 10));
+        gol.typ.lose_hp(gol.hp());
+        let mut entities = vec![Entity::player(Vector::new(1, 1)), gol];
 
-                                    _ => { },
-                                }
-                            }
-                        },
+        assert_eq!(monster_moves(&mut entities, 2), vec![0, 0]);
+        assert_eq!(entities[1].energy, 0);
+        assert_eq!(entities[1].pos, Vector::new(10, 10));
+    }
 
-                        Trap::Bump => {
-                            let pos = entity.pos;
-                            let x_offset = rng.gen_range(-1, 2);
-                            let y_offset = rng.gen_range(-1, 2);
-                            entity.pos =
-                                attempt_move(pos,
-                                             Vector::new(x_offset, y_offset),
-                                             &map);
-                        }
+    /// A puzzle mode game on a level of its own, with the undo history started
+    fn puzzle_game(entities: Vec<Entity>) -> Game {
+        let mut game = Game::new().unwrap();
+        game.puzzle_mode = true;
+        game.map = vec![Tile { pos: Vector::new(0, 0), glyph: WALL_CHAR, color: WHITE, blocks: true }];
+        game.entities = entities;
+        game.player_id = 0;
+        game.game_state = GameState::Playing(0);
+        game.stats = RunStats::new();
+        game.rng = StdRng::seed_from_u64(7);
+        game.reset_history();
+        return game;
+    }
 
-                        Trap::CountDown(n) => {
-                            if n == 0 {
-                                entity.typ.lose_hp(5);
-                            } else {
-                                count_downs.push((trap_index, n - 1));
-                            }
-                        },
+    #[test]
+    fn undo_restores_the_turn_before() {
+        let mut game = puzzle_game(vec![Entity::player(Vector::new(3, 3)),
+                                        found_trap(Vector::new(4, 3), Trap::Bump),
+                                        Entity::gol(Vector::new(10, 10))]);
+        let map = game.map.clone();
+        let entities = game.entities.clone();
+        let next_roll = game.rng.clone().gen::<u64>();
+
+        game.play_action(Some(Action::Move(Direction::Right)), 0);
+        let landing = game.entities[0].pos;
+        assert_eq!(game.stats.turns, 1);
+        assert_ne!(game.entities[2].pos, Vector::new(10, 10));
+
+        game.undo();
+
+        assert_eq!(game.map, map);
+        assert_eq!(game.entities, entities);
+        assert_eq!(game.game_state, GameState::Playing(0));
+        assert_eq!(game.stats.turns, 0);
+        assert_eq!(game.rng.clone().gen::<u64>(), next_roll);
+        assert_eq!(game.undo_count, 1);
+
+        // the rng is rewound as well, so the bump trap lands the player in the same place
+        game.play_action(Some(Action::Move(Direction::Right)), 0);
+        assert_eq!(game.entities[0].pos, landing);
+    }
 
-                        Trap::NextLevel => {
-                            if entity.typ.is_player() {
-                                *next_level = true;
-                            }
-                        }
+    #[test]
+    fn undo_from_game_over_returns_to_the_turn_before_death() {
+        let mut game = puzzle_game(vec![Entity::player(Vector::new(1, 1)), found_trap(Vector::new(2, 1), Trap::Kill)]);
 
-                        Trap::Win => {
-                            if entity.typ.is_player() {
-                                *win = true;
-                            }
-                        }
+        game.play_action(Some(Action::Move(Direction::Right)), 0);
+        assert_eq!(game.game_state, GameState::Lost);
+        game.game_state = GameState::GameOver;
 
-                        Trap::Arrow(dir) => {
-                            let x_dir;
-                            let y_dir;
-                            match dir {
-                                Arrow::Left => {
-                                    x_dir = -1;
-                                    y_dir = 0;
-                                },
+        game.undo();
 
-                                Arrow::Right => {
-                                    x_dir = 1;
-                                    y_dir = 0;
-                                },
+        assert_eq!(game.game_state, GameState::Playing(0));
+        assert_eq!(game.entities[0].pos, Vector::new(1, 1));
+        assert_eq!(game.entities[0].hp(), 5);
+        assert!(game.animations.is_empty());
+    }
 
-                                Arrow::Up => {
-                                    x_dir = 0;
-                                    y_dir = -1;
-                                },
+    #[test]
+    fn undo_stops_at_the_start_of_the_level() {
+        let mut game = puzzle_game(vec![Entity::player(Vector::new(3, 3))]);
 
-                                Arrow::Down => {
-                                    x_dir = 0;
-                                    y_dir = 1;
-                                },
-                            }
+        game.undo();
 
-                            let mut cur_pos = entity.pos;
-                            let mut prev_pos = entity.pos;
-                            cur_pos += Vector::new(x_dir, y_dir);
-                            while !blocked_tile(cur_pos, map) &&
-                                  occupied_tile(cur_pos, &entities_clone) == None {
-                                prev_pos = cur_pos;
-                                cur_pos += Vector::new(x_dir, y_dir);
-                            }
-                            if occupied_tile(cur_pos, &entities_clone).map(|ent| ent.typ.is_trap()).unwrap_or(false) {
-                                prev_pos = cur_pos;
-                            }
+        assert_eq!(game.entities[0].pos, Vector::new(3, 3));
+        assert_eq!(game.undo_count, 0);
+    }
 
-                            moves.push((prev_pos, index));
-                        }
-                    }
-                },
+    #[test]
+    fn reveal_traps_within_detection_radius() {
+        let mut entities = vec![Entity::player(Vector::new(5, 5)),
+                                Entity::trap(Vector::new(6, 5), Trap::Kill),
+                                Entity::trap(Vector::new(6, 6), Trap::Kill),
+                                Entity::trap(Vector::new(5, 7), Trap::Kill),
+                                Entity::trap(Vector::new(7, 6), Trap::Kill)];
+
+        reveal_traps(&mut entities, Vector::new(5, 5), TRAP_DETECTION_RADIUS);
+
+        // next to the player, and on the diagonal at about 1.41 tiles
+        assert!(!entities[1].hidden);
+        assert!(!entities[2].hidden);
+        // two tiles away, straight or at a knight's move
+        assert!(entities[3].hidden);
+        assert!(entities[4].hidden);
+    }
 
-                _ => panic!("Unreachable?"),
-            }
-        }
+    #[test]
+    fn resolve_traps_monster_trigger_reveals_the_trap() {
+        let mut entities = vec![Entity::player(Vector::new(1, 1)),
+                                Entity::gol(Vector::new(8, 8)),
+                                Entity::trap(Vector::new(8, 8), Trap::Berserk)];
+        assert!(entities[2].hidden);
+        let mut events = Vec::new();
+
+        resolve_traps(&mut entities, &Vec::new(), &mut StdRng::seed_from_u64(1), &mut events, &mut false, &mut false);
+
+        assert!(!entities[2].hidden);
+        assert!(matches!(events[0], GameEvent::TrapTriggered(TrapTrigger { trap: Trap::Berserk, typ: EntityType::Monster(_), .. })));
     }
 
+    #[test]
+    fn bindings_round_trip_through_config() {
+        let mut bindings = default_bindings();
+        bindings.iter_mut().find(|(action, _keys)| *action == Action::Undo).unwrap().1 = vec![Key::U];
+        let config = Config { bindings: bindings_to_config(&bindings), ..Config::default() };
 
-    for (pos, index) in moves {
-        entities[index].pos = pos;
+        assert_eq!(bindings_from_config(&config), bindings);
+        assert_eq!(bindings_from_config(&Config::default()), default_bindings());
     }
 
-    for (ix, new_n) in count_downs.iter() {
-        entities[*ix].typ = EntityType::Trap(Trap::CountDown(*new_n));
-        entities[*ix].glyph = ('0' as u8 + *new_n) as char;
+    #[test]
+    fn bindings_with_unknown_keys_fall_back_to_defaults() {
+        let saved = vec![Binding { action: Action::Undo, keys: vec!["NotAKey".to_string()] },
+                         Binding { action: Action::Wait, keys: vec!["NotAKey".to_string(), "Space".to_string()] }];
+        let config = Config { bindings: saved, ..Config::default() };
+
+        let bindings = bindings_from_config(&config);
+        let keys_for = |wanted: Action| bindings.iter().find(|(action, _keys)| *action == wanted).unwrap().1.clone();
+        let default_keys_for = |wanted: Action| default_bindings().into_iter().find(|(action, _keys)| *action == wanted).unwrap().1;
+
+        assert_eq!(keys_for(Action::Undo), default_keys_for(Action::Undo));
+        assert_eq!(keys_for(Action::Wait), vec![Key::Space]);
     }
 
-    removals.sort();
-    removals.reverse();
-    for index in removals.iter() {
-        match entities[*index].typ {
-            EntityType::Monster(monster) => {
-                dbg!();
-                animations.push(Animation::MonsterDeath(monster.typ,
-                                                        entities[*index].pos,
-                                                        0));
-            }
+    /// A player that has stepped right, then slid two more tiles
+    fn stepped_and_slid_player() -> Entity {
+        let mut player = Entity::player(Vector::new(1, 1));
+        player.pos = Vector::new(2, 1);
+        player.tween_to_pos();
+        player.pos = Vector::new(4, 1);
+        player.push_tween(Tween::Slide(Vector::new(2, 1), Vector::new(4, 1)));
+        return player;
+    }
 
-            _ => (),
-        }
-        entities.swap_remove(*index);
+    #[test]
+    fn tweens_queue_behind_each_other() {
+        let mut player = stepped_and_slid_player();
+        // already shown sliding to its position, so there is no step to add
+        player.tween_to_pos();
+
+        assert_eq!(player.tweens, vec![Tween::Step(Vector::new(1, 1), Vector::new(2, 1)),
+                                       Tween::Slide(Vector::new(2, 1), Vector::new(4, 1))]);
+        assert_eq!(player.last_pos, Vector::new(4, 1));
+        assert_eq!(player.time_until_shown(), ITERP_TIME + SLIDE_STEP_TIME);
     }
-}
 
-fn draw_tile(tile: &Tile, window: &mut Window, offset_px: Vector, char_map: &mut Asset<HashMap<u32, Image>>, noise: &mut Perlin) {
-    let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
-    let pos_px = tile.pos.times(tile_size_px);
-    let pos = offset_px + pos_px;
-    let color_noise =
-        noise.get([6.0 * (pos.x as f64 / WINDOW_WIDTH as f64),
-                   6.0 * (pos.y as f64 / WINDOW_HEIGHT as f64)]);
-    let mut tile_color = lerp_color(DARK_GRAY, LIGHT_GRAY, color_noise as f32);
-    if tile.blocks {
-        tile_color = LIGHT_GRAY;
+    #[test]
+    fn tweens_play_in_order() {
+        let mut player = stepped_and_slid_player();
+        assert_eq!(player.display(), (Vector::new(1, 1), 1.0));
+
+        player.advance_tweens(ITERP_TIME / 2.0);
+        assert_eq!(player.display(), (Vector::new(1.5, 1.0), 1.0));
+
+        player.advance_tweens(ITERP_TIME / 2.0);
+        assert_eq!(player.tweens.len(), 1);
+        assert_eq!(player.display(), (Vector::new(2, 1), 1.0));
+
+        player.advance_tweens(SLIDE_STEP_TIME);
+        assert!(player.tweens.is_empty());
+        assert_eq!(player.tween_time, 0.0);
+        assert_eq!(player.display(), (Vector::new(4, 1), 1.0));
     }
 
-    char_map.execute(|char_map| {
-        draw_char(&char_map, window, pos, tile.glyph, tile_color);
-        Ok(())
-    });
-}
+    #[test]
+    fn input_during_motion_waits_for_it_to_finish() {
+        let mut game = puzzle_game(vec![Entity::player(Vector::new(3, 3))]);
 
-fn draw_entity(entity: &Entity,
-               pos: Vector,
-               window: &mut Window,
-               char_map: &mut Asset<HashMap<u32, Image>>) {
-    let _color = 
-        match entity.typ {
-            EntityType::Monster(monster) => {
-                if monster.status == Some(Status::Berserk) {
-                    RED
-                } else {
-                    entity.color
-                }
-            }
+        game.play_input(Some(Action::Move(Direction::Right)), 0);
+        assert_eq!(game.entities[0].pos, Vector::new(4, 3));
+        assert!(game.motion_pending());
 
-            EntityType::Player(player) => {
-                if player.status == Some(Status::Berserk) {
-                    RED
-                } else {
-                    entity.color
-                }
-            }
+        // only the latest press is kept
+        game.play_input(Some(Action::Move(Direction::Up)), 0);
+        game.play_input(Some(Action::Move(Direction::Down)), 0);
+        assert_eq!(game.entities[0].pos, Vector::new(4, 3));
+        assert_eq!(game.buffered_action, Some(Action::Move(Direction::Down)));
 
-            _ => {
-                entity.color
-            }
-        };
+        game.entities[0].advance_tweens(ITERP_TIME);
+        game.play_input(None, 0);
 
-    char_map.execute(|char_map| {
-        draw_char(&char_map, window, pos, entity.glyph, entity.color);
-        return Ok(());
-    }).unwrap();
-}
+        assert_eq!(game.entities[0].pos, Vector::new(4, 4));
+        assert_eq!(game.buffered_action, None);
+        assert_eq!(game.stats.turns, 2);
+    }
 
-// draw functions
-fn draw_char(char_map: &HashMap<u32, Image>, window: &mut Window, pos: Vector, chr: char, color: Color) {
-    let char_ix = chr as u32;
-    let rect = Rectangle::new(pos, Vector::new(16, 16));
-    window.draw_ex(&rect,
-                   Blended(&char_map[&char_ix], color),
-                   Transform::scale(Vector::new(SCALE, SCALE)),
-                   SCALE);
-}
+    #[test]
+    fn input_during_motion_stops_walking() {
+        let mut game = puzzle_game(vec![Entity::player(Vector::new(3, 3))]);
+        game.play_input(Some(Action::Move(Direction::Right)), 0);
+        game.walk_target = Some(Vector::new(8, 3));
 
-fn main() {
-    // NOTE: Set HIDPI to 1.0 to get pixel-perfect rendering.
-    // Otherwise the window resizes to whatever value the OS sets and
-    // scales the contents.
-    // https://docs.rs/glutin/0.19.0/glutin/dpi/index.html
-    std::env::set_var("WINIT_HIDPI_FACTOR", "1.0");
+        game.play_input(Some(Action::Wait), 0);
 
-    let settings = Settings {
-        // If the graphics do need to be scaled (e.g. using
-        // `with_center`), blur them. This looks better with fonts.
-        scale: quicksilver::graphics::ImageScaleStrategy::Blur,
-        draw_rate: 100.0,
-        update_rate: MILLIS_PER_UPDATE,
-        ..Default::default()
-    };
-    run::<Game>("Stone Fall", Vector::new(WINDOW_WIDTH, WINDOW_HEIGHT), settings);
+        assert_eq!(game.walk_target, None);
+        assert_eq!(game.buffered_action, Some(Action::Wait));
+    }
 }
@@ -0,0 +1,421 @@
+//! A second frontend that plays the same rules in a terminal, drawing the map
+//! as colored characters and reading keys from the TTY. Start it with
+//! `cargo run -- --terminal`, and add `--seed N` to replay a seeded run.
+
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+
+use crossterm::{cursor, event, style, terminal, queue, execute};
+use crossterm::event::{KeyCode, KeyEventKind, KeyModifiers};
+
+use super::*;
+
+
+// each tile is two characters wide so the map comes out roughly square
+const TILE_COLUMNS: u16 = 2;
+const PANEL_COLUMN: u16 = MAP_WIDTH as u16 * TILE_COLUMNS + 3;
+const HELP_ROW: u16 = LOG_VISIBLE_LINES as u16 + 5;
+
+/// Code page 437 characters for codes below 32
+const CP437_LOW: &str = " ☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+/// Code page 437 characters for codes 128 and up
+const CP437_HIGH: &str =
+    "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»\
+     ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+     αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■ ";
+
+/// Codes that the tileset draws as its own pictures rather than the code page character
+const TILESET_GLYPHS: [(char, char); 9] = [
+    (WALL_CHAR, '#'),
+    (PLAYER_CHARACTER, '@'),
+    (GOL_CHAR, 'g'),
+    (ROOK_CHAR, 'r'),
+    (KILL_TRAP_CHAR, '^'),
+    (ARROW_UP_CHAR, '▲'),
+    (ARROW_DOWN_CHAR, '▼'),
+    (NEXT_LEVEL_CHAR, '>'),
+    // the McMuffin that wins the game
+    (WIN_CHAR, '$'),
+];
+
+/// Actions that work in the terminal, checked in order like PLAYING_ACTIONS
+const TERMINAL_ACTIONS: [Action; 13] =
+    [Action::Cancel, Action::Restart, Action::ScrollLogUp, Action::ScrollLogDown,
+     Action::Move(Direction::Left), Action::Move(Direction::Right),
+     Action::Move(Direction::Up), Action::Move(Direction::Down),
+     Action::Move(Direction::UpLeft), Action::Move(Direction::UpRight),
+     Action::Move(Direction::DownLeft), Action::Move(Direction::DownRight),
+     Action::Wait];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TerminalState {
+    Playing(usize),
+    Won,
+    Lost,
+}
+
+/// A run played in the terminal. This holds only what the rules need; the
+/// animations, audio and menus belong to the window frontend.
+struct TerminalGame {
+    seed: u64,
+    rng: StdRng,
+    map: Map,
+    entities: Vec<Entity>,
+    player_id: EntityId,
+    state: TerminalState,
+    events: Vec<GameEvent>,
+    log: MessageLog,
+    stats: RunStats,
+}
+
+impl TerminalGame {
+    fn new(seed: u64) -> TerminalGame {
+        let mut game = TerminalGame {
+            seed: seed,
+            rng: StdRng::seed_from_u64(seed),
+            map: Vec::new(),
+            entities: vec![Entity::player(Vector::new(0, 0))],
+            player_id: 0,
+            state: TerminalState::Playing(0),
+            events: Vec::new(),
+            log: MessageLog::new(),
+            stats: RunStats::new(),
+        };
+        game.generate_level(0);
+
+        return game;
+    }
+
+    /// Generate the map and entities for a level, keeping the player
+    fn generate_level(&mut self, level: usize) {
+        self.map = generate_map(Vector::new(MAP_WIDTH as u8, MAP_HEIGHT as u8), &mut self.rng);
+
+        let player = self.entities[self.player_id].clone();
+        self.entities.clear();
+        self.entities.push(player);
+        let player_pos = generate_entities(level + 1 == NUM_LEVEL_GAME, &mut self.entities, &self.map, &mut self.rng);
+        self.entities[self.player_id].pos = player_pos;
+        self.entities[self.player_id].snap_to_pos();
+        reveal_traps(&mut self.entities, player_pos, TRAP_DETECTION_RADIUS);
+        self.log.add(format!("You enter level {}", level + 1));
+    }
+
+    fn take_turn(&mut self, action: Action, level: usize) {
+        let mut next_level = false;
+        let mut win = false;
+        let took_turn = play_turn(&mut self.entities,
+                                  &self.map,
+                                  &mut self.rng,
+                                  self.player_id,
+                                  action,
+                                  &mut self.events,
+                                  &mut next_level,
+                                  &mut win);
+        if !took_turn {
+            return;
+        }
+        self.stats.take_turn();
+
+        if next_level || win {
+            self.events.push(GameEvent::LevelCleared(level));
+        }
+
+        for event in self.events.drain(..) {
+            self.stats.on_event(&event);
+            self.log.on_event(&event);
+        }
+
+        // nothing is animated here, so everything is shown where it is straight away
+        for entity in self.entities.iter_mut() {
+            entity.snap_to_pos();
+        }
+        self.entities.retain(|entity| !(entity.typ.is_monster() && entity.hp() <= 0));
+
+        if self.entities[self.player_id].hp() <= 0 {
+            self.state = TerminalState::Lost;
+        } else if win || (next_level && level + 1 >= NUM_LEVEL_GAME) {
+            self.state = TerminalState::Won;
+        } else if next_level {
+            self.generate_level(level + 1);
+            self.stats.start_level();
+            self.state = TerminalState::Playing(level + 1);
+        }
+    }
+
+    /// Handle an action, returning false once the player quits
+    fn update(&mut self, action: Action) -> bool {
+        match action {
+            Action::Cancel => return false,
+
+            Action::Restart => *self = TerminalGame::new(random_seed()),
+
+            Action::ScrollLogUp => self.log.scroll_by(LOG_VISIBLE_LINES as i32 / 2),
+
+            Action::ScrollLogDown => self.log.scroll_by(-(LOG_VISIBLE_LINES as i32 / 2)),
+
+            _ => {
+                if let TerminalState::Playing(level) = self.state {
+                    self.take_turn(action, level);
+                }
+            },
+        }
+
+        return true;
+    }
+
+    fn draw<W: Write>(&self, out: &mut W, palette: &Palette, noise: &Perlin) -> std::io::Result<()> {
+        // every cell drawn is overwritten in place, see clear_screen
+        queue!(out, cursor::MoveTo(0, 0))?;
+
+        // draw map, with each entity over the floor it stands on
+        let tile_size_px = Vector::new(TILE_WIDTH_PX, TILE_HEIGHT_PX);
+        let offset_px = Vector::new(MAP_DRAW_X_OFFSET as u32, MAP_DRAW_Y_OFFSET as u32);
+        for tile in self.map.iter() {
+            let floor_color = tile_color(tile, offset_px + tile.pos.times(tile_size_px), noise, palette);
+            let (glyph, color) =
+                match self.entity_at(tile.pos) {
+                    Some(entity) => (entity.glyph, palette.entity_color(entity)),
+                    None => (tile.glyph, floor_color),
+                };
+
+            let column = tile.pos.x as u16 * TILE_COLUMNS;
+            let row = tile.pos.y as u16;
+            let back = if tile.blocks { palette.background } else { floor_color };
+            queue!(out,
+                   cursor::MoveTo(column, row),
+                   style::SetForegroundColor(terminal_color(color)),
+                   style::SetBackgroundColor(terminal_color(back)),
+                   style::Print(terminal_char(glyph)),
+                   style::SetForegroundColor(terminal_color(floor_color)),
+                   style::Print(if tile.blocks { terminal_char(tile.glyph) } else { ' ' }))?;
+        }
+
+        // draw the status panel and the message log beside the map
+        let player = &self.entities[self.player_id];
+        let mut lines = Vec::new();
+        lines.push(match self.state {
+            TerminalState::Playing(level) => format!("Stone Fall  level {}/{}", level + 1, NUM_LEVEL_GAME),
+            TerminalState::Won => "You won! R for a new run".to_string(),
+            TerminalState::Lost => "You died. R for a new run".to_string(),
+        });
+        lines.push(format!("HP {}/{}  seed {}", player.hp().max(0), player.max_hp(), self.seed));
+        for monster in self.entities.iter().filter(|entity| entity.typ.is_monster()) {
+            if let EntityType::Monster(stats) = monster.typ {
+                let status = monster.status().map(|status| format!(" {}", status.icon())).unwrap_or(String::new());
+                lines.push(format!("{} {} HP {}/{}{}",
+                                   terminal_char(monster.glyph), stats.typ.name(), monster.hp(), monster.max_hp(), status));
+            }
+        }
+        lines.push(String::new());
+        lines.extend(self.log.visible_lines());
+
+        queue!(out,
+               style::SetForegroundColor(terminal_color(palette.text)),
+               style::SetBackgroundColor(terminal_color(palette.background)))?;
+        // blank the rest of each row too, in case the last frame had longer or more lines
+        let rows = (lines.len() as u16).max(HELP_ROW);
+        for row in 0..rows {
+            let line = lines.get(row as usize).map(|line| line.as_str()).unwrap_or("");
+            queue!(out,
+                   cursor::MoveTo(PANEL_COLUMN, row),
+                   style::Print(line),
+                   terminal::Clear(terminal::ClearType::UntilNewLine))?;
+        }
+
+        queue!(out,
+               cursor::MoveTo(0, HELP_ROW),
+               style::SetForegroundColor(terminal_color(palette.text_disabled)),
+               style::Print("Move with your bound keys, Esc quits, R restarts, PageUp/PageDown scroll the log"),
+               style::ResetColor)?;
+
+        return out.flush();
+    }
+
+    /// What is drawn on a tile: the player over monsters over known traps
+    fn entity_at(&self, pos: Vector) -> Option<&Entity> {
+        let here = || self.entities.iter().filter(|entity| entity.pos == pos && !entity.hidden);
+        return here().find(|entity| entity.typ.is_player())
+                     .or(here().find(|entity| entity.typ.is_monster()))
+                     .or(here().find(|entity| entity.typ.is_trap()));
+    }
+}
+
+/// The character to show for one of the tileset's glyph codes
+fn terminal_char(glyph: char) -> char {
+    if let Some((_code, chr)) = TILESET_GLYPHS.iter().find(|(code, _chr)| *code == glyph) {
+        return *chr;
+    }
+
+    let code = glyph as u32;
+    return match code {
+        0..=31 => CP437_LOW.chars().nth(code as usize).unwrap(),
+        127 => '⌂',
+        128..=255 => CP437_HIGH.chars().nth(code as usize - 128).unwrap(),
+        _ => glyph,
+    };
+}
+
+fn terminal_color(color: Color) -> style::Color {
+    let channel = |value: f32| (clamp(0.0, 1.0, value) * 255.0) as u8;
+    return style::Color::Rgb { r: channel(color.r), g: channel(color.g), b: channel(color.b) };
+}
+
+/// The bindable key with the same name as a terminal key, so the key bindings
+/// from the settings file work here too
+fn key_from_terminal(code: KeyCode) -> Option<Key> {
+    let name =
+        match code {
+            KeyCode::Char(' ') => "Space".to_string(),
+            KeyCode::Char('.') => "Period".to_string(),
+            KeyCode::Char(',') => "Comma".to_string(),
+            KeyCode::Char(chr) if chr.is_ascii_alphabetic() => chr.to_ascii_uppercase().to_string(),
+            KeyCode::Char(chr) if chr.is_ascii_digit() => format!("Key{}", chr),
+            KeyCode::Left => "Left".to_string(),
+            KeyCode::Right => "Right".to_string(),
+            KeyCode::Up => "Up".to_string(),
+            KeyCode::Down => "Down".to_string(),
+            KeyCode::PageUp => "PageUp".to_string(),
+            KeyCode::PageDown => "PageDown".to_string(),
+            KeyCode::Enter => "Return".to_string(),
+            KeyCode::Backspace => "Back".to_string(),
+            KeyCode::Tab => "Tab".to_string(),
+            KeyCode::Esc => "Escape".to_string(),
+            _ => return None,
+        };
+
+    return key_from_name(&name);
+}
+
+/// The first of the candidate actions bound to the key
+fn terminal_action(bindings: &Bindings, key: Key, candidates: &[Action]) -> Option<Action> {
+    return candidates.iter().find(|candidate| keys_for(bindings, **candidate).contains(&key)).copied();
+}
+
+/// The static folder beside the executable, or in the source tree when the
+/// game was built there, so the game can be started from any directory
+fn static_dir() -> PathBuf {
+    let beside_exe = std::env::current_exe().ok().and_then(|exe| exe.parent().map(|dir| dir.join("static")));
+    return match beside_exe {
+        Some(dir) if dir.is_dir() => dir,
+        _ => Path::new(env!("CARGO_MANIFEST_DIR")).join("static"),
+    };
+}
+
+fn read_palette(path: &Path) -> std::result::Result<Palette, String> {
+    let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+    let file = serde_json::from_slice::<PaletteFile>(&bytes).map_err(|err| err.to_string())?;
+    return Palette::from_file(file);
+}
+
+/// The palette chosen in the settings, read straight from the static folder
+fn load_palette(name: &str) -> Palette {
+    let dir = static_dir();
+    for file_name in PALETTE_FILES.iter() {
+        let path = dir.join(file_name);
+        match read_palette(&path) {
+            Ok(palette) => {
+                if palette.name == name {
+                    return palette;
+                }
+            },
+
+            Err(err) => eprintln!("Could not read {}: {}", path.display(), err),
+        }
+    }
+
+    if name != Palette::default().name {
+        eprintln!("No palette named {}, using the default", name);
+    }
+    return Palette::default();
+}
+
+/// Play in the terminal until the player quits, putting the terminal back afterwards
+pub fn run() -> std::io::Result<()> {
+    let config = load_config();
+    let bindings = bindings_from_config(&config);
+    let palette = load_palette(&config.palette);
+    let noise = Perlin::new();
+
+    let args: Vec<String> = std::env::args().collect();
+    let seed =
+        args.iter()
+            .position(|arg| arg == "--seed")
+            .and_then(|index| args.get(index + 1))
+            .and_then(|seed| seed.parse::<u64>().ok())
+            .unwrap_or(random_seed());
+    let mut game = TerminalGame::new(seed);
+
+    let mut out = stdout();
+    terminal::enable_raw_mode()?;
+    let _guard = TerminalGuard;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+    // restore the terminal before a panic message is printed, so it is not
+    // lost with the alternate screen
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+
+    return play(&mut game, &mut out, &bindings, &palette, &noise);
+}
+
+/// Leaves raw mode and the alternate screen however the game ends
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+fn restore_terminal() {
+    // nothing more can be done if the terminal will not reset
+    let _ = execute!(stdout(), style::ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+}
+
+/// Fill the terminal with the background color. Only done at the start and on
+/// a resize, as clearing before every frame makes the screen flicker.
+fn clear_screen<W: Write>(out: &mut W, palette: &Palette) -> std::io::Result<()> {
+    queue!(out,
+           style::SetBackgroundColor(terminal_color(palette.background)),
+           terminal::Clear(terminal::ClearType::All))?;
+    return Ok(());
+}
+
+fn play<W: Write>(game: &mut TerminalGame, out: &mut W, bindings: &Bindings, palette: &Palette, noise: &Perlin) -> std::io::Result<()> {
+    clear_screen(out, palette)?;
+    game.draw(out, palette, noise)?;
+
+    loop {
+        match event::read()? {
+            event::Event::Key(key_event) => {
+                // some terminals report releases too
+                if key_event.kind != KeyEventKind::Press {
+                    continue;
+                }
+
+                // raw mode swallows the interrupt, so ctrl-c quits by hand
+                if key_event.modifiers.contains(KeyModifiers::CONTROL) && key_event.code == KeyCode::Char('c') {
+                    return Ok(());
+                }
+
+                let action = key_from_terminal(key_event.code).and_then(|key| terminal_action(bindings, key, &TERMINAL_ACTIONS));
+                if let Some(action) = action {
+                    if !game.update(action) {
+                        return Ok(());
+                    }
+                }
+            },
+
+            event::Event::Resize(_, _) => clear_screen(out, palette)?,
+
+            _ => continue,
+        }
+
+        game.draw(out, palette, noise)?;
+    }
+}